@@ -32,6 +32,7 @@ fn main() {
     #[cfg(windows)]
     add_def(&mut defines, "XD3_WIN32", "1");
     add_def(&mut defines, "SHELL_TESTS", "0");
+    add_def(&mut defines, "XD3_DEBUG", "0");
 
     #[cfg(feature = "lzma")]
     {
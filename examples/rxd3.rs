@@ -1,4 +1,4 @@
-use async_std::fs::File;
+use std::fs::File;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -20,21 +20,17 @@ struct Opt {
     output: String,
 }
 
-async fn run(opt: Opt) {
-    let input = File::open(&opt.input).await.expect("File::open");
-    let source = File::open(&opt.source).await.expect("File::open");
-    let out = File::create(&opt.output).await.expect("File::create");
+fn run(opt: Opt) {
+    let input = File::open(&opt.input).expect("File::open");
+    let source = File::open(&opt.source).expect("File::open");
+    let out = File::create(&opt.output).expect("File::create");
 
     match opt.mode {
         Mode::Decode => {
-            xdelta3::stream::decode_async(input, source, out)
-                .await
-                .expect("failed to decode");
+            xdelta3::sync::decode(input, source, out).expect("failed to decode");
         }
         Mode::Encode => {
-            xdelta3::stream::encode_async(input, source, out)
-                .await
-                .expect("failed to encode");
+            xdelta3::sync::encode(input, source, out).expect("failed to encode");
         }
     }
 }
@@ -44,5 +40,5 @@ fn main() {
 
     let opt = Opt::from_args();
 
-    async_std::task::block_on(run(opt));
+    run(opt);
 }
@@ -0,0 +1,82 @@
+//! The application header layout of the `xdelta3` command line.
+
+use std::str;
+
+use crate::{Error, Result};
+
+/// Application header in the layout written by the `xdelta3` command line.
+///
+/// The command line stores the name and the external compressor of the target and of
+/// the source, separated by slashes: `target/compression/source/compression`. Without a
+/// source, only the first two fields are written. Missing fields are empty strings. Only
+/// the last field of four may contain a slash.
+///
+/// ```
+/// use xdelta3::CliAppHeader;
+///
+/// let header = CliAppHeader::parse(b"file_v2.bin//file_v1.bin/").unwrap();
+/// assert_eq!(header.target_name, "file_v2.bin");
+/// assert_eq!(header.source_name, "file_v1.bin");
+/// assert_eq!(header.to_bytes().unwrap(), b"file_v2.bin//file_v1.bin/");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CliAppHeader {
+    pub target_name: String,
+    pub target_compression: String,
+    pub source_name: String,
+    pub source_compression: String,
+}
+
+impl CliAppHeader {
+    /// Parses an application header, returning `None` if it is not in this layout.
+    pub fn parse(header: &[u8]) -> Option<Self> {
+        let header = str::from_utf8(header).ok()?;
+        // Like the command line, split at the first three slashes only, so the last field
+        // may contain slashes.
+        let fields: Vec<&str> = header.splitn(4, '/').collect();
+        match fields[..] {
+            [target_name, target_compression] => Some(Self {
+                target_name: target_name.to_owned(),
+                target_compression: target_compression.to_owned(),
+                ..Self::default()
+            }),
+            [target_name, target_compression, source_name, source_compression] => Some(Self {
+                target_name: target_name.to_owned(),
+                target_compression: target_compression.to_owned(),
+                source_name: source_name.to_owned(),
+                source_compression: source_compression.to_owned(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Formats the header the way the command line writes it.
+    ///
+    /// Returns [`Error::InvalidConfig`] if a field other than `source_compression`
+    /// contains a slash, since [`parse`](Self::parse) would then split it at the wrong
+    /// place.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let fields = [
+            ("target_name", &self.target_name),
+            ("target_compression", &self.target_compression),
+            ("source_name", &self.source_name),
+        ];
+        if let Some((name, _)) = fields.iter().find(|(_, field)| field.contains('/')) {
+            return Err(Error::InvalidConfig {
+                msg: format!("{} must not contain a slash", name),
+            });
+        }
+        let header = if self.source_name.is_empty() && self.source_compression.is_empty() {
+            format!("{}/{}", self.target_name, self.target_compression)
+        } else {
+            format!(
+                "{}/{}/{}/{}",
+                self.target_name,
+                self.target_compression,
+                self.source_name,
+                self.source_compression
+            )
+        };
+        Ok(header.into_bytes())
+    }
+}
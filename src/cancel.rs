@@ -0,0 +1,39 @@
+//! Cooperative cancellation of long encodes and decodes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Handle for stopping an encode or decode from another thread or task.
+///
+/// Clones share the same state. Once [`cancel`](Self::cancel) has been called, an encode
+/// or decode configured with this token stops at the end of the current window, or before
+/// it reads more input, and returns [`Error::Cancelled`](crate::Error::Cancelled).
+///
+/// ```
+/// use xdelta3::{CancelToken, EncoderConfig, Error};
+///
+/// let token = CancelToken::new();
+/// let config = EncoderConfig::new().cancel_token(token.clone());
+/// token.cancel();
+/// let result = xdelta3::encode_with(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7], &config);
+/// assert!(matches!(result, Err(Error::Cancelled { .. })));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks every encode and decode using this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true once [`cancel`](Self::cancel) has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,597 @@
+//! I/O-free encode and decode loop shared by the streaming front ends.
+//!
+//! A [`Codec`] owns the `xd3_stream`, the input buffer and the source blocks. The front
+//! ends call [`Codec::step`] and do whatever I/O it asks for: read input into
+//! [`Codec::input_buf`], write out [`Codec::output`], or read the requested block into
+//! [`Codec::source_mut`].
+
+use super::binding;
+use super::cancel::CancelToken;
+use super::config::Settings;
+use super::progress::{Observer, Progress};
+use super::srcbuf::SrcBuffer;
+use super::stats::{InstructionStats, Stats};
+use super::xd3::{Mode, Xd3Stream, XD3_DEFAULT_WINSIZE};
+use super::{DecoderConfig, EncoderConfig, Error, Position, Result};
+use log::debug;
+use std::time::{Duration, Instant};
+
+/// What the front end has to do before calling [`Codec::step`] again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// Read input into [`Codec::input_buf`] and report it with [`Codec::provide_input`].
+    NeedInput,
+    /// Write out [`Codec::output`] and report it with [`Codec::consume_output`].
+    Output,
+    /// Read the source block with this number and answer with `SrcBuffer::getblk`.
+    NeedSource(usize),
+    /// The decoder has read the file header.
+    GotHeader,
+    /// A window is about to be processed.
+    WindowStart,
+    /// A window has been processed and all of its output consumed.
+    WindowFinish,
+    /// The whole input has been processed.
+    Done,
+}
+
+pub(crate) struct Codec {
+    // The stream keeps pointers into everything below, so it is declared, and dropped, first.
+    stream: Xd3Stream,
+    src: Option<Box<SrcBuffer>>,
+    app_header: Option<Vec<u8>>,
+    input: Vec<u8>,
+    mode: Mode,
+    block_size: usize,
+    progress: Option<Observer>,
+    cancel: Option<CancelToken>,
+    total_target_len: Option<u64>,
+    /// Number of windows finished, all of whose output has been consumed.
+    windows: u64,
+    /// Offset into the current output of the bytes not yet consumed.
+    out_pos: usize,
+    /// Output consumed so far.
+    output_bytes: u64,
+    /// Time spent in [`step`](Self::step).
+    codec_time: Duration,
+    eof: bool,
+    done: bool,
+}
+
+impl Codec {
+    /// Configures a stream. If `source` is set, the source is attached by
+    /// [`attach_source`](Self::attach_source) once its first blocks have been read.
+    pub(crate) fn new(mode: Mode, mut settings: Settings<'_>, source: bool) -> Result<Self> {
+        if settings.xd3.winsize == 0 {
+            settings.xd3.winsize = XD3_DEFAULT_WINSIZE as u32;
+        }
+        let mut stream = Xd3Stream::new();
+        stream.config(&mut settings.xd3)?;
+
+        let src = if source {
+            Some(Box::new(SrcBuffer::new(
+                settings.src_winsize,
+                settings.src_blksize,
+            )))
+        } else {
+            None
+        };
+
+        let input_buf_size = stream.winsize as usize;
+        debug!("stream.winsize={}", input_buf_size);
+
+        let mut codec = Self {
+            stream,
+            src,
+            app_header: settings.app_header.map(<[u8]>::to_vec),
+            input: vec![0u8; input_buf_size],
+            mode,
+            block_size: settings.src_blksize,
+            progress: settings.progress.cloned(),
+            cancel: settings.cancel.cloned(),
+            total_target_len: settings.total_target_len,
+            windows: 0,
+            out_pos: 0,
+            output_bytes: 0,
+            codec_time: Duration::default(),
+            eof: false,
+            done: false,
+        };
+        if let Some(header) = &codec.app_header {
+            // The header is owned by the codec, which outlives the stream.
+            unsafe { codec.stream.set_app_header(header) };
+        }
+        Ok(codec)
+    }
+
+    /// Size of a source block.
+    pub(crate) fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The source blocks, if the codec was created with a source.
+    pub(crate) fn source_mut(&mut self) -> Option<&mut SrcBuffer> {
+        self.src.as_deref_mut()
+    }
+
+    /// Attaches the source to the stream, telling xdelta3 its length if it is known by now.
+    pub(crate) fn attach_source(&mut self) -> Result<()> {
+        if let Some(buf) = &mut self.src {
+            // An empty source is the same as none at all.
+            let src_len = buf.len();
+            buf.fit_window();
+            if src_len != Some(0) {
+                // The source is boxed and owned by the codec, which outlives the stream.
+                unsafe { self.stream.set_source(&mut buf.src, src_len)? };
+            }
+        }
+        Ok(())
+    }
+
+    /// The buffer to read the next piece of input into.
+    pub(crate) fn input_buf(&mut self) -> &mut [u8] {
+        &mut self.input
+    }
+
+    /// Hands the first `len` bytes of [`input_buf`](Self::input_buf) to xdelta3.
+    ///
+    /// A length of zero marks the end of the input.
+    pub(crate) fn provide_input(&mut self, len: usize) {
+        debug!("read_size={}", len);
+        if len == 0 {
+            // xd3_set_flags
+            self.stream.flags |= binding::xd3_flags::XD3_FLUSH as i32;
+            self.eof = true;
+        }
+        self.stream.avail_input(&self.input[..len]);
+    }
+
+    /// Length of [`input_buf`](Self::input_buf): the window size.
+    pub(crate) fn input_size(&self) -> usize {
+        self.input.len()
+    }
+
+    /// Hands a copy of `data`, at most [`input_size`](Self::input_size) bytes, to xdelta3
+    /// as the next piece of input.
+    pub(crate) fn provide_input_from(&mut self, data: &[u8]) {
+        self.input[..data.len()].copy_from_slice(data);
+        self.provide_input(data.len());
+    }
+
+    /// Returns true if xdelta3 has consumed all the input handed to it.
+    pub(crate) fn input_consumed(&self) -> bool {
+        self.stream.avail_in == 0
+    }
+
+    /// The application header read by the decoder, once [`Step::GotHeader`] has been returned.
+    pub(crate) fn app_header(&self) -> Option<&[u8]> {
+        self.stream.app_header()
+    }
+
+    /// The output not yet consumed.
+    pub(crate) fn output(&self) -> &[u8] {
+        &self.stream.output()[self.out_pos..]
+    }
+
+    /// Marks the first `len` bytes of [`output`](Self::output) as written.
+    pub(crate) fn consume_output(&mut self, len: usize) {
+        self.out_pos += len;
+        self.output_bytes += len as u64;
+        if self.out_pos == self.stream.output().len() {
+            self.stream.consume_output();
+            self.out_pos = 0;
+        }
+    }
+
+    pub(crate) fn position(&self) -> Position {
+        self.stream.position()
+    }
+
+    /// Fails with [`Error::Cancelled`] if the cancel token has been cancelled.
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled {
+                windows: self.windows,
+                position: Some(self.position()),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Calls the progress observer, if there is one.
+    fn report(&self, window_finished: bool) {
+        if let Some(observer) = &self.progress {
+            let source_bytes = self.src.as_ref().map_or(0, |buf| buf.bytes_read());
+            observer.report(&Progress::new(
+                &self.stream,
+                self.mode,
+                self.output_bytes,
+                source_bytes,
+                self.total_target_len,
+                window_finished,
+            ));
+        }
+    }
+
+    /// Statistics of the run so far, with `io_time` spent by the front end on I/O.
+    pub(crate) fn stats(&self, io_time: Duration) -> Stats {
+        let instructions = match self.mode {
+            Mode::Encode => Some(InstructionStats::new(&self.stream)),
+            Mode::Decode => None,
+        };
+        Stats {
+            input_bytes: self.stream.total_in,
+            source_bytes: self.src.as_ref().map_or(0, |buf| buf.bytes_read()),
+            output_bytes: self.output_bytes,
+            windows: self.windows,
+            instructions,
+            codec_time: self.codec_time,
+            io_time,
+        }
+    }
+
+    /// Runs xdelta3 until it needs something from the front end.
+    ///
+    /// While output is left over from the last step, this returns [`Step::Output`] again.
+    pub(crate) fn step(&mut self) -> Result<Step> {
+        let started = Instant::now();
+        let step = self.run();
+        self.codec_time += started.elapsed();
+        step
+    }
+
+    fn run(&mut self) -> Result<Step> {
+        if !self.output().is_empty() {
+            return Ok(Step::Output);
+        }
+        loop {
+            if self.done {
+                return Ok(Step::Done);
+            }
+
+            use binding::xd3_rvalues::*;
+            match self.stream.step(self.mode)? {
+                XD3_INPUT => {
+                    if !self.eof {
+                        self.check_cancelled()?;
+                        return Ok(Step::NeedInput);
+                    }
+                    self.done = true;
+                    self.stream.close(self.mode)?;
+                }
+                XD3_OUTPUT => {
+                    if !self.output().is_empty() {
+                        return Ok(Step::Output);
+                    }
+                    self.stream.consume_output();
+                }
+                XD3_GETSRCBLK => {
+                    // xdelta3 only asks for source blocks once a source is set
+                    let stream = &self.stream;
+                    let buf = self
+                        .src
+                        .as_mut()
+                        .ok_or_else(|| stream.error(XD3_INTERNAL as i32))?;
+                    let blkno = buf.requested();
+                    if buf.needs_block(blkno) {
+                        return Ok(Step::NeedSource(blkno));
+                    }
+                    buf.getblk();
+                }
+                XD3_GOTHEADER => {
+                    self.report(false);
+                    return Ok(Step::GotHeader);
+                }
+                XD3_WINSTART => {
+                    self.report(false);
+                    return Ok(Step::WindowStart);
+                }
+                XD3_WINFINISH => {
+                    self.windows += 1;
+                    self.report(true);
+                    self.check_cancelled()?;
+                    return Ok(Step::WindowFinish);
+                }
+                _ => {
+                    // step() returns nothing else
+                }
+            }
+        }
+    }
+}
+
+/// What an [`Encoder`] or [`Decoder`] needs from the caller, or reports, after a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// More input is needed: call `provide_input`, or `finish_input` at the end of the input.
+    NeedInput,
+    /// Output is ready. It stays valid until the next call to `step`.
+    Output(&'a [u8]),
+    /// The source block with this number is needed: call `provide_source_block`.
+    NeedSourceBlock(u64),
+    /// The decoder has read the file header (`XD3_GOTHEADER`).
+    GotHeader,
+    /// A window is about to be processed (`XD3_WINSTART`).
+    WindowStart,
+    /// A window has been processed and all of its output returned (`XD3_WINFINISH`).
+    WindowFinish,
+    /// The whole input has been processed and the stream is closed.
+    Done,
+}
+
+/// State shared by [`Encoder`] and [`Decoder`].
+struct Incremental {
+    codec: Codec,
+    /// Whether the output returned by the last step is still to be consumed.
+    output_returned: bool,
+    /// The source block xdelta3 is waiting for.
+    pending_block: Option<usize>,
+}
+
+impl Incremental {
+    fn new(mode: Mode, settings: Settings<'_>, source_len: Option<Option<u64>>) -> Result<Self> {
+        let mut codec = Codec::new(mode, settings, source_len.is_some())?;
+        if let (Some(buf), Some(Some(len))) = (codec.source_mut(), source_len) {
+            buf.set_len(len);
+        }
+        codec.attach_source()?;
+        Ok(Self {
+            codec,
+            output_returned: false,
+            pending_block: None,
+        })
+    }
+
+    fn block_size(&self) -> usize {
+        self.codec.block_size()
+    }
+
+    fn provide_input(&mut self, data: &[u8]) {
+        assert!(
+            self.codec.input_consumed(),
+            "provide_input called before xdelta3 asked for more input"
+        );
+        assert!(
+            data.len() <= self.codec.input_size(),
+            "provide_input called with {} bytes, more than the window size of {}",
+            data.len(),
+            self.codec.input_size()
+        );
+        // An empty piece would end the input, which only `finish_input` does.
+        if !data.is_empty() {
+            self.codec.provide_input_from(data);
+        }
+    }
+
+    fn finish_input(&mut self) {
+        self.codec.provide_input(0);
+    }
+
+    fn provide_source_block(&mut self, blkno: u64, data: &[u8]) {
+        // Storing a block xdelta3 has not asked for could evict or move the block its
+        // `curblk` points to, so any other block is ignored.
+        if self.pending_block != Some(blkno as usize) {
+            return;
+        }
+        let blkno = blkno as usize;
+        let buf = match self.codec.source_mut() {
+            Some(buf) => buf,
+            None => return,
+        };
+        if buf.needs_block(blkno) {
+            let block = buf.next_block(blkno);
+            let len = block.len().min(data.len());
+            block[..len].copy_from_slice(&data[..len]);
+            buf.push_block(blkno, len);
+        }
+        buf.getblk();
+        self.pending_block = None;
+    }
+
+    fn input_size(&self) -> usize {
+        self.codec.input_size()
+    }
+
+    fn step(&mut self) -> Result<Event<'_>> {
+        if self.output_returned {
+            let len = self.codec.output().len();
+            self.codec.consume_output(len);
+            self.output_returned = false;
+        }
+        if let Some(blkno) = self.pending_block {
+            return Ok(Event::NeedSourceBlock(blkno as u64));
+        }
+
+        Ok(match self.codec.step()? {
+            Step::NeedInput => Event::NeedInput,
+            Step::Output => {
+                self.output_returned = true;
+                Event::Output(self.codec.output())
+            }
+            Step::NeedSource(blkno) => {
+                self.pending_block = Some(blkno);
+                Event::NeedSourceBlock(blkno as u64)
+            }
+            Step::GotHeader => Event::GotHeader,
+            Step::WindowStart => Event::WindowStart,
+            Step::WindowFinish => Event::WindowFinish,
+            Step::Done => Event::Done,
+        })
+    }
+}
+
+/// Incremental encoder that does no I/O of its own
+///
+/// The caller runs the encoder with [`step`](Self::step) and answers each [`Event`]: it
+/// hands over the updated data with [`provide_input`](Self::provide_input) and
+/// [`finish_input`](Self::finish_input), the original data with
+/// [`provide_source_block`](Self::provide_source_block), and takes the patch from
+/// [`Event::Output`]. This is the loop the streaming functions run, for callers that
+/// bring their own event loop.
+///
+/// ```
+/// use xdelta3::{Encoder, EncoderConfig, Event};
+///
+/// let input = [1, 2, 3, 4, 5, 6, 7];
+/// let src = [1, 2, 4, 4, 7, 6, 7];
+/// let mut encoder = Encoder::with_source(&EncoderConfig::new(), Some(src.len() as u64)).unwrap();
+/// let mut patch = Vec::new();
+/// let mut input_done = false;
+/// loop {
+///     match encoder.step().unwrap() {
+///         Event::NeedInput if !input_done => {
+///             encoder.provide_input(&input);
+///             input_done = true;
+///         }
+///         Event::NeedInput => encoder.finish_input(),
+///         Event::Output(data) => patch.extend_from_slice(data),
+///         Event::NeedSourceBlock(blkno) => {
+///             let start = blkno as usize * encoder.block_size();
+///             let end = src.len().min(start + encoder.block_size());
+///             encoder.provide_source_block(blkno, &src[start.min(end)..end]);
+///         }
+///         Event::Done => break,
+///         _ => {}
+///     }
+/// }
+/// assert_eq!(xdelta3::decode(&patch, &src).unwrap(), input);
+/// ```
+pub struct Encoder {
+    inner: Incremental,
+}
+
+impl Encoder {
+    /// Creates an encoder without a source, like [`compress`](crate::compress).
+    pub fn new(config: &EncoderConfig) -> Result<Self> {
+        let inner = Incremental::new(Mode::Encode, config.settings()?, None)?;
+        Ok(Self { inner })
+    }
+
+    /// Creates an encoder with a source of `len` bytes, or of unknown length.
+    ///
+    /// When the length is not known, the source ends at the first block shorter than
+    /// [`block_size`](Self::block_size).
+    pub fn with_source(config: &EncoderConfig, len: Option<u64>) -> Result<Self> {
+        let inner = Incremental::new(Mode::Encode, config.settings()?, Some(len))?;
+        Ok(Self { inner })
+    }
+
+    /// Size of a source block. Block `n` starts at byte `n * block_size()` of the source.
+    pub fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    /// Largest piece of input [`provide_input`](Self::provide_input) takes at once: the
+    /// window size.
+    pub fn input_size(&self) -> usize {
+        self.inner.input_size()
+    }
+
+    /// Hands the next piece of the updated data to the encoder.
+    ///
+    /// An empty `data` is ignored: the input only ends with `finish_input`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the encoder has not consumed the previous input, that is if `step` has not
+    /// returned [`Event::NeedInput`] since, or if `data` is longer than
+    /// [`input_size`](Self::input_size).
+    pub fn provide_input(&mut self, data: &[u8]) {
+        self.inner.provide_input(data)
+    }
+
+    /// Marks the end of the updated data.
+    pub fn finish_input(&mut self) {
+        self.inner.finish_input()
+    }
+
+    /// Hands source block `blkno` to the encoder.
+    ///
+    /// `data` must be the whole block, and only be shorter than
+    /// [`block_size`](Self::block_size) for the last block of the source. Only the block
+    /// asked for by the last [`Event::NeedSourceBlock`] is taken; any other is ignored.
+    pub fn provide_source_block(&mut self, blkno: u64, data: &[u8]) {
+        self.inner.provide_source_block(blkno, data)
+    }
+
+    /// Runs the encoder until it needs something from the caller or has output.
+    pub fn step(&mut self) -> Result<Event<'_>> {
+        self.inner.step()
+    }
+}
+
+/// Incremental decoder that does no I/O of its own
+///
+/// This works like [`Encoder`]: the patch is handed over with
+/// [`provide_input`](Self::provide_input), the original data with
+/// [`provide_source_block`](Self::provide_source_block), and the patched data comes out
+/// of [`Event::Output`].
+pub struct Decoder {
+    inner: Incremental,
+}
+
+impl Decoder {
+    /// Creates a decoder without a source, like [`decompress`](crate::decompress).
+    pub fn new(config: &DecoderConfig) -> Result<Self> {
+        let inner = Incremental::new(Mode::Decode, config.settings()?, None)?;
+        Ok(Self { inner })
+    }
+
+    /// Creates a decoder with a source of `len` bytes, or of unknown length.
+    ///
+    /// When the length is not known, the source ends at the first block shorter than
+    /// [`block_size`](Self::block_size).
+    pub fn with_source(config: &DecoderConfig, len: Option<u64>) -> Result<Self> {
+        let inner = Incremental::new(Mode::Decode, config.settings()?, Some(len))?;
+        Ok(Self { inner })
+    }
+
+    /// Size of a source block. Block `n` starts at byte `n * block_size()` of the source.
+    pub fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    /// Largest piece of input [`provide_input`](Self::provide_input) takes at once: the
+    /// window size.
+    pub fn input_size(&self) -> usize {
+        self.inner.input_size()
+    }
+
+    /// Hands the next piece of the patch to the decoder.
+    ///
+    /// An empty `data` is ignored: the input only ends with `finish_input`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the decoder has not consumed the previous input, that is if `step` has not
+    /// returned [`Event::NeedInput`] since, or if `data` is longer than
+    /// [`input_size`](Self::input_size).
+    pub fn provide_input(&mut self, data: &[u8]) {
+        self.inner.provide_input(data)
+    }
+
+    /// Marks the end of the patch.
+    pub fn finish_input(&mut self) {
+        self.inner.finish_input()
+    }
+
+    /// Hands source block `blkno` to the decoder.
+    ///
+    /// `data` must be the whole block, and only be shorter than
+    /// [`block_size`](Self::block_size) for the last block of the source. Only the block
+    /// asked for by the last [`Event::NeedSourceBlock`] is taken; any other is ignored.
+    pub fn provide_source_block(&mut self, blkno: u64, data: &[u8]) {
+        self.inner.provide_source_block(blkno, data)
+    }
+
+    /// Runs the decoder until it needs something from the caller or has output.
+    pub fn step(&mut self) -> Result<Event<'_>> {
+        self.inner.step()
+    }
+
+    /// Returns the application header of the patch, once [`Event::GotHeader`] has been
+    /// returned.
+    pub fn app_header(&self) -> Option<&[u8]> {
+        self.inner.codec.app_header()
+    }
+}
@@ -0,0 +1,648 @@
+//! Settings for the encoder and the decoder.
+
+use std::sync::OnceLock;
+
+use super::binding;
+use super::cancel::CancelToken;
+use super::progress::{Observer, Progress};
+use super::xd3::{Xd3Stream, XD3_HARDMAXWINSIZE};
+use super::{Error, Result};
+
+// xdelta3 refuses windows smaller than its allocation unit (`XD3_ALLOCSIZE`)
+// or larger than `XD3_HARDMAXWINSIZE`.
+const MIN_WINDOW_SIZE: usize = 1 << 14;
+const MAX_WINDOW_SIZE: usize = XD3_HARDMAXWINSIZE;
+// Shortest match xdelta3 encodes (`MIN_MATCH`).
+const MIN_MATCH: u32 = 4;
+
+// Source buffering of the streaming functions.
+const XD3_DEFAULT_SRCWINSZ: usize = 1 << 26;
+const DEFAULT_SRCBLKSZ: usize = 1 << 20;
+const MIN_SRCBLKSZ: usize = 1 << 14;
+const MAX_SRCBLKSZ: usize = 1 << 30;
+
+/// String-matcher presets (`xd3_smatch_cfg`).
+///
+/// Faster matchers look for fewer and shorter matches and produce larger patches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Matcher {
+    Fastest,
+    Faster,
+    Fast,
+    Default,
+    Slow,
+    /// Tunable matcher, see [`MatcherParams`]. Without further tuning it searches like
+    /// [`Matcher::Default`].
+    Soft,
+}
+
+impl Matcher {
+    /// The preset the reference `xdelta3` command line picks for a compression level.
+    fn for_level(level: u32) -> Self {
+        match level {
+            0 | 1 => Matcher::Fastest,
+            2 => Matcher::Faster,
+            3..=5 => Matcher::Fast,
+            6 => Matcher::Default,
+            _ => Matcher::Slow,
+        }
+    }
+
+    fn apply(self, params: Option<&MatcherParams>, cfg: &mut binding::xd3_config) {
+        use binding::xd3_smatch_cfg::*;
+        cfg.smatch_cfg = match self {
+            Matcher::Fastest => XD3_SMATCH_FASTEST,
+            Matcher::Faster => XD3_SMATCH_FASTER,
+            Matcher::Fast => XD3_SMATCH_FAST,
+            Matcher::Default => XD3_SMATCH_DEFAULT,
+            Matcher::Slow => XD3_SMATCH_SLOW,
+            Matcher::Soft => XD3_SMATCH_SOFT,
+        };
+        if self == Matcher::Soft {
+            let params = params.copied().unwrap_or_default();
+            let soft = &mut cfg.smatcher_soft;
+            soft.large_look = params.large_look;
+            soft.large_step = params.large_step;
+            soft.small_look = params.small_look;
+            soft.small_chain = params.small_chain;
+            soft.small_lchain = params.small_lchain;
+            soft.max_lazy = params.max_lazy;
+            soft.long_enough = params.long_enough;
+        }
+    }
+}
+
+/// Parameters of the tunable string matcher (`xd3_smatcher`).
+///
+/// The encoder finds matches in the source by indexing a checksum of `large_look` bytes
+/// every `large_step` bytes, and matches within the target with a checksum of
+/// `small_look` bytes. The defaults are those of [`Matcher::Default`].
+///
+/// ```
+/// use xdelta3::{EncoderConfig, MatcherParams};
+///
+/// // Index the source more densely and search longer chains.
+/// let params = MatcherParams {
+///     large_step: 2,
+///     small_chain: 32,
+///     ..MatcherParams::default()
+/// };
+/// assert!(params.validate().is_ok());
+/// let config = EncoderConfig::new().matcher_params(params);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatcherParams {
+    /// Length of the checksum used to find matches in the source. At least 4.
+    pub large_look: u32,
+    /// Distance between the source positions that are indexed. At least 1.
+    pub large_step: u32,
+    /// Length of the checksum used to find matches in the target. At least 4.
+    pub small_look: u32,
+    /// Number of earlier positions tried for each target match. At least 1.
+    pub small_chain: u32,
+    /// Number of earlier positions tried while a lazy match is pending.
+    /// Between 1 and `small_chain`.
+    pub small_lchain: u32,
+    /// Matches shorter than this are retried one byte later. At most `long_enough`; zero
+    /// turns lazy matching off.
+    pub max_lazy: u32,
+    /// Matches at least this long are taken without looking further. At least 4.
+    pub long_enough: u32,
+}
+
+impl Default for MatcherParams {
+    /// The parameters of [`Matcher::Default`], as xdelta3 sets them up from its `default`
+    /// template.
+    fn default() -> Self {
+        static DEFAULT: OnceLock<MatcherParams> = OnceLock::new();
+        *DEFAULT.get_or_init(|| {
+            let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
+            Matcher::Default.apply(None, &mut cfg);
+            let mut stream = Xd3Stream::new();
+            stream
+                .config(&mut cfg)
+                .expect("xdelta3 rejected its default matcher");
+            let matcher = &stream.smatcher;
+            MatcherParams {
+                large_look: matcher.large_look,
+                large_step: matcher.large_step,
+                small_look: matcher.small_look,
+                small_chain: matcher.small_chain,
+                small_lchain: matcher.small_lchain,
+                max_lazy: matcher.max_lazy,
+                long_enough: matcher.long_enough,
+            }
+        })
+    }
+}
+
+impl MatcherParams {
+    /// Checks that every parameter is in range.
+    ///
+    /// The encoding functions perform the same check and fail with
+    /// [`Error::InvalidConfig`].
+    pub fn validate(&self) -> Result<()> {
+        if self.large_look < MIN_MATCH {
+            return Err(invalid(format!(
+                "large_look {} is less than {}",
+                self.large_look, MIN_MATCH
+            )));
+        }
+        if self.large_step == 0 {
+            return Err(invalid("large_step must not be zero".to_owned()));
+        }
+        if self.small_look < MIN_MATCH {
+            return Err(invalid(format!(
+                "small_look {} is less than {}",
+                self.small_look, MIN_MATCH
+            )));
+        }
+        if self.small_chain == 0 {
+            return Err(invalid("small_chain must not be zero".to_owned()));
+        }
+        if self.small_lchain == 0 || self.small_lchain > self.small_chain {
+            return Err(invalid(format!(
+                "small_lchain {} out of range 1-{}",
+                self.small_lchain, self.small_chain
+            )));
+        }
+        if self.long_enough < MIN_MATCH {
+            return Err(invalid(format!(
+                "long_enough {} is less than {}",
+                self.long_enough, MIN_MATCH
+            )));
+        }
+        if self.max_lazy > self.long_enough {
+            return Err(invalid(format!(
+                "max_lazy {} is greater than long_enough {}",
+                self.max_lazy, self.long_enough
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Secondary compressors that can be applied to the sections of a patch.
+///
+/// Secondary compression makes patches smaller at the cost of encoding and decoding speed.
+/// A patch made with a compressor can only be applied by a build that includes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecondaryCompression {
+    /// Static Huffman coding (`XD3_SEC_DJW`).
+    Djw,
+    /// Adaptive Huffman coding (`XD3_SEC_FGK`).
+    Fgk,
+    /// LZMA (`XD3_SEC_LZMA`), available with the `lzma` feature.
+    Lzma,
+}
+
+impl SecondaryCompression {
+    /// Returns the compressors included in this build.
+    pub fn available() -> &'static [SecondaryCompression] {
+        #[cfg(feature = "lzma")]
+        {
+            &[
+                SecondaryCompression::Djw,
+                SecondaryCompression::Fgk,
+                SecondaryCompression::Lzma,
+            ]
+        }
+        #[cfg(not(feature = "lzma"))]
+        {
+            &[SecondaryCompression::Djw, SecondaryCompression::Fgk]
+        }
+    }
+
+    /// Returns whether this build includes the compressor.
+    pub fn is_available(self) -> bool {
+        Self::available().contains(&self)
+    }
+
+    fn flag(self) -> i32 {
+        use binding::xd3_flags::*;
+        (match self {
+            SecondaryCompression::Djw => XD3_SEC_DJW,
+            SecondaryCompression::Fgk => XD3_SEC_FGK,
+            SecondaryCompression::Lzma => XD3_SEC_LZMA,
+        }) as i32
+    }
+}
+
+/// Sections of a patch that secondary compression is applied to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SecondarySections {
+    /// The bytes added by ADD and RUN instructions.
+    pub data: bool,
+    /// The instructions.
+    pub instructions: bool,
+    /// The addresses of COPY instructions.
+    pub addresses: bool,
+}
+
+impl Default for SecondarySections {
+    fn default() -> Self {
+        Self {
+            data: true,
+            instructions: true,
+            addresses: true,
+        }
+    }
+}
+
+/// Everything the code driving an `xd3_stream` needs from a configuration.
+pub(crate) struct Settings<'a> {
+    pub(crate) xd3: binding::xd3_config,
+    pub(crate) app_header: Option<&'a [u8]>,
+    /// Size of the source window, a multiple of `src_blksize`.
+    pub(crate) src_winsize: usize,
+    /// Size of a source block, a power of two.
+    pub(crate) src_blksize: usize,
+    pub(crate) progress: Option<&'a Observer>,
+    pub(crate) cancel: Option<&'a CancelToken>,
+    /// Length of the whole target, reported in [`Progress`].
+    pub(crate) total_target_len: Option<u64>,
+}
+
+/// Settings for generating patches.
+///
+/// ```
+/// use xdelta3::{encode_with, EncoderConfig, Matcher};
+///
+/// let config = EncoderConfig::new().level(9).matcher(Matcher::Slow);
+/// let patch = encode_with(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7], &config);
+/// assert!(patch.is_ok());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EncoderConfig {
+    level: Option<u32>,
+    no_compress: bool,
+    matcher: Option<Matcher>,
+    matcher_params: Option<MatcherParams>,
+    window_size: Option<usize>,
+    iopt_size: Option<usize>,
+    sprevsz: Option<usize>,
+    secondary: Option<SecondaryCompression>,
+    secondary_sections: SecondarySections,
+    checksum: bool,
+    app_header: Option<Vec<u8>>,
+    source_window_size: Option<usize>,
+    source_block_size: Option<usize>,
+    progress: Option<Observer>,
+    cancel: Option<CancelToken>,
+}
+
+impl EncoderConfig {
+    /// Creates a configuration with xdelta3's defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the compression level, from 0 (fastest) to 9 (smallest patches).
+    ///
+    /// Like the `-0` to `-9` options of the `xdelta3` command line, the level selects a
+    /// matcher unless one is set with [`matcher`](Self::matcher). Level 0 also turns off
+    /// compression of the patch.
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Stores the target without looking for matches (`XD3_NOCOMPRESS`).
+    pub fn no_compress(mut self, no_compress: bool) -> Self {
+        self.no_compress = no_compress;
+        self
+    }
+
+    /// Selects the string-matcher preset.
+    pub fn matcher(mut self, matcher: Matcher) -> Self {
+        self.matcher = Some(matcher);
+        self
+    }
+
+    /// Selects the tunable matcher ([`Matcher::Soft`]) with the given parameters.
+    pub fn matcher_params(mut self, params: MatcherParams) -> Self {
+        self.matcher = Some(Matcher::Soft);
+        self.matcher_params = Some(params);
+        self
+    }
+
+    /// Sets the size of the input window, between 16 KiB and 16 MiB.
+    pub fn window_size(mut self, size: usize) -> Self {
+        self.window_size = Some(size);
+        self
+    }
+
+    /// Sets the number of instructions buffered for optimization; 0 means unlimited.
+    pub fn iopt_size(mut self, size: usize) -> Self {
+        self.iopt_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the buffer used to find matches within the target.
+    /// It is rounded up to a power of two.
+    pub fn sprevsz(mut self, size: usize) -> Self {
+        self.sprevsz = Some(size);
+        self
+    }
+
+    /// Compresses the patch further with a secondary compressor.
+    pub fn secondary(mut self, compression: SecondaryCompression) -> Self {
+        self.secondary = Some(compression);
+        self
+    }
+
+    /// Selects the sections the secondary compressor is applied to. All of them by default.
+    pub fn secondary_sections(mut self, sections: SecondarySections) -> Self {
+        self.secondary_sections = sections;
+        self
+    }
+
+    /// Stores an Adler-32 checksum of every target window in the patch (`XD3_ADLER32`).
+    ///
+    /// The decoder uses it to detect a wrong or damaged source. Off by default.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Stores arbitrary bytes in the header of the patch.
+    ///
+    /// They can be read back with [`app_header`](crate::app_header) without applying the
+    /// patch. [`CliAppHeader`](crate::CliAppHeader) formats the header the way the `xdelta3`
+    /// command line does.
+    pub fn app_header(mut self, header: impl Into<Vec<u8>>) -> Self {
+        self.app_header = Some(header.into());
+        self
+    }
+
+    /// Sets how much of the source the streaming encoder keeps in memory and searches
+    /// for matches, like the `-B` option of the `xdelta3` command line. 64 MiB by default.
+    ///
+    /// A larger window finds matches farther apart at the cost of memory. The window is
+    /// rounded up to a multiple of the block size, and never made larger than the source.
+    pub fn source_window_size(mut self, size: usize) -> Self {
+        self.source_window_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the blocks the source is read in, a power of two of at least
+    /// 16 KiB. 1 MiB by default.
+    pub fn source_block_size(mut self, size: usize) -> Self {
+        self.source_block_size = Some(size);
+        self
+    }
+
+    /// Calls `f` with the [`Progress`] made so far when the file header has been read and
+    /// when each window starts and finishes.
+    pub fn progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Observer::new(f));
+        self
+    }
+
+    /// Stops processing with [`Error::Cancelled`] once `token` is cancelled. The token is
+    /// checked at the end of each window and before each read of the input.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub(crate) fn settings(&self) -> Result<Settings<'_>> {
+        let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
+
+        if self.checksum {
+            cfg.flags |= binding::xd3_flags::XD3_ADLER32 as i32;
+        }
+        if let Some(level) = self.level {
+            if level > 9 {
+                return Err(invalid(format!(
+                    "compression level {} out of range 0-9",
+                    level
+                )));
+            }
+            if level == 0 {
+                cfg.flags |= binding::xd3_flags::XD3_NOCOMPRESS as i32;
+            }
+            cfg.flags |= (level << binding::xd3_flags::XD3_COMPLEVEL_SHIFT as u32) as i32;
+        }
+        if self.no_compress {
+            cfg.flags |= binding::xd3_flags::XD3_NOCOMPRESS as i32;
+        }
+
+        if let Some(matcher) = self.matcher.or_else(|| self.level.map(Matcher::for_level)) {
+            if let Some(params) = &self.matcher_params {
+                params.validate()?;
+            }
+            matcher.apply(self.matcher_params.as_ref(), &mut cfg);
+        }
+
+        if let Some(size) = self.window_size {
+            cfg.winsize = window_size(size)?;
+        }
+        if let Some(size) = self.iopt_size {
+            cfg.iopt_size = to_usize_t(size, "iopt_size")?;
+        }
+        if let Some(size) = self.sprevsz {
+            if size == 0 {
+                return Err(invalid("sprevsz must not be zero".to_owned()));
+            }
+            cfg.sprevsz = to_usize_t(size, "sprevsz")?;
+        }
+
+        if let Some(secondary) = self.secondary {
+            if !secondary.is_available() {
+                return Err(Error::NoSecondary {
+                    msg: format!("{:?} is not included in this build", secondary),
+                    position: None,
+                });
+            }
+            use binding::xd3_flags::*;
+            let sections = self.secondary_sections;
+            cfg.flags |= secondary.flag();
+            if !sections.data {
+                cfg.flags |= XD3_SEC_NODATA as i32;
+            }
+            if !sections.instructions {
+                cfg.flags |= XD3_SEC_NOINST as i32;
+            }
+            if !sections.addresses {
+                cfg.flags |= XD3_SEC_NOADDR as i32;
+            }
+        }
+
+        let (src_winsize, src_blksize) =
+            source_sizes(self.source_window_size, self.source_block_size)?;
+        Ok(Settings {
+            xd3: cfg,
+            app_header: self.app_header.as_deref(),
+            src_winsize,
+            src_blksize,
+            progress: self.progress.as_ref(),
+            cancel: self.cancel.as_ref(),
+            total_target_len: None,
+        })
+    }
+}
+
+/// Settings for applying patches.
+#[derive(Debug, Clone)]
+pub struct DecoderConfig {
+    window_size: Option<usize>,
+    verify_checksum: bool,
+    source_window_size: Option<usize>,
+    source_block_size: Option<usize>,
+    progress: Option<Observer>,
+    cancel: Option<CancelToken>,
+    total_target_len: Option<u64>,
+}
+
+impl Default for DecoderConfig {
+    fn default() -> Self {
+        Self {
+            window_size: None,
+            verify_checksum: true,
+            source_window_size: None,
+            source_block_size: None,
+            progress: None,
+            cancel: None,
+            total_target_len: None,
+        }
+    }
+}
+
+impl DecoderConfig {
+    /// Creates a configuration with xdelta3's defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the size of the buffer the patch is read into, between 16 KiB and 16 MiB.
+    pub fn window_size(mut self, size: usize) -> Self {
+        self.window_size = Some(size);
+        self
+    }
+
+    /// Checks the Adler-32 checksums stored in the patch, if any. On by default.
+    ///
+    /// A window whose checksum does not match is reported as [`Error::ChecksumMismatch`].
+    pub fn verify_checksum(mut self, verify: bool) -> Self {
+        self.verify_checksum = verify;
+        self
+    }
+
+    /// Sets how much of the source the streaming decoder keeps in memory. 64 MiB by default.
+    ///
+    /// It should be at least the source window the patch was made with. The window is
+    /// rounded up to a multiple of the block size, and never made larger than the source.
+    pub fn source_window_size(mut self, size: usize) -> Self {
+        self.source_window_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the blocks the source is read in, a power of two of at least
+    /// 16 KiB. 1 MiB by default.
+    pub fn source_block_size(mut self, size: usize) -> Self {
+        self.source_block_size = Some(size);
+        self
+    }
+
+    /// Calls `f` with the [`Progress`] made so far when the file header has been read and
+    /// when each window starts and finishes.
+    pub fn progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Observer::new(f));
+        self
+    }
+
+    /// Stops processing with [`Error::Cancelled`] once `token` is cancelled. The token is
+    /// checked at the end of each window and before each read of the input.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Sets the length of the whole target, reported as [`Progress::total_target_len`].
+    ///
+    /// The in-memory functions read it from the window headers of the patch. For the
+    /// streaming functions, it can be read with [`target_len`](crate::target_len) from a
+    /// patch in memory, or with [`sync::target_len`](crate::sync::target_len) from a
+    /// seekable one.
+    pub fn total_target_len(mut self, len: u64) -> Self {
+        self.total_target_len = Some(len);
+        self
+    }
+
+    pub(crate) fn settings(&self) -> Result<Settings<'_>> {
+        let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
+        if !self.verify_checksum {
+            cfg.flags |= binding::xd3_flags::XD3_ADLER32_NOVER as i32;
+        }
+        if let Some(size) = self.window_size {
+            cfg.winsize = window_size(size)?;
+        }
+        let (src_winsize, src_blksize) =
+            source_sizes(self.source_window_size, self.source_block_size)?;
+        Ok(Settings {
+            xd3: cfg,
+            app_header: None,
+            src_winsize,
+            src_blksize,
+            progress: self.progress.as_ref(),
+            cancel: self.cancel.as_ref(),
+            total_target_len: self.total_target_len,
+        })
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidConfig { msg }
+}
+
+fn window_size(size: usize) -> Result<u32> {
+    if !(MIN_WINDOW_SIZE..=MAX_WINDOW_SIZE).contains(&size) {
+        return Err(invalid(format!(
+            "window size {} out of range {}-{}",
+            size, MIN_WINDOW_SIZE, MAX_WINDOW_SIZE
+        )));
+    }
+    Ok(size as u32)
+}
+
+/// Returns the source window and block sizes, with defaults filled in.
+fn source_sizes(window: Option<usize>, block: Option<usize>) -> Result<(usize, usize)> {
+    let window = window.unwrap_or(XD3_DEFAULT_SRCWINSZ);
+    let block = match block {
+        Some(block) => {
+            if !block.is_power_of_two() || !(MIN_SRCBLKSZ..=MAX_SRCBLKSZ).contains(&block) {
+                return Err(invalid(format!(
+                    "source block size {} is not a power of two in range {}-{}",
+                    block, MIN_SRCBLKSZ, MAX_SRCBLKSZ
+                )));
+            }
+            block
+        }
+        None => {
+            let mut block = DEFAULT_SRCBLKSZ;
+            while block > MIN_SRCBLKSZ && block > window {
+                block /= 2;
+            }
+            block
+        }
+    };
+    let blocks = window
+        .checked_add(block - 1)
+        .map(|n| (n / block).max(1))
+        .ok_or_else(|| invalid(format!("source window size {} is too large", window)))?;
+    Ok((blocks * block, block))
+}
+
+fn to_usize_t(size: usize, name: &str) -> Result<u32> {
+    if size > u32::MAX as usize {
+        return Err(invalid(format!("{} {} is too large", name, size)));
+    }
+    Ok(size as u32)
+}
@@ -0,0 +1,259 @@
+//! Error type shared by the in-memory and streaming APIs.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+
+use libc::{c_char, c_int};
+
+use super::binding;
+
+// Message libxdelta3 sets when the Adler-32 checksum of a decoded window does not match
+// (xdelta3-decode.h). It comes with `XD3_INVALID_INPUT`, like every other corrupt patch,
+// so the message is all that tells them apart; `checksum_mismatch_message` below keeps
+// this string in step with the library.
+const CHECKSUM_MISMATCH_MSG: &str = "target window checksum mismatch";
+
+/// Position in the stream at which xdelta3 reported an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    /// Number of the window being processed, counting from zero.
+    pub window: u64,
+    /// Number of input bytes consumed by xdelta3 when the error occurred.
+    pub offset: u64,
+}
+
+/// Errors returned by the encoding and decoding functions.
+///
+/// Errors reported by xdelta3 itself carry the message libxdelta3 produced and,
+/// when it is known, the [`Position`] of the failure.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the input (the target data or the patch) failed.
+    Input(io::Error),
+    /// Reading the source (the original data) failed.
+    Source(io::Error),
+    /// Writing the output failed.
+    Output(io::Error),
+    /// The input is not a valid VCDIFF patch, or it is corrupt or truncated.
+    InvalidInput {
+        msg: String,
+        position: Option<Position>,
+    },
+    /// The Adler-32 checksum of a decoded target window does not match the patch.
+    ChecksumMismatch {
+        msg: String,
+        position: Option<Position>,
+    },
+    /// The patch needs a secondary compressor that this build does not include (`XD3_NOSECOND`).
+    NoSecondary {
+        msg: String,
+        position: Option<Position>,
+    },
+    /// The patch copies from a part of the source that is no longer available (`XD3_TOOFARBACK`).
+    TooFarBack {
+        msg: String,
+        position: Option<Position>,
+    },
+    /// The encoder or decoder configuration is invalid (`XD3_INVALID`).
+    InvalidConfig { msg: String },
+    /// xdelta3 reported an internal error or an unimplemented feature, or a step could not
+    /// be run, such as when the tokio runtime shuts down.
+    Internal {
+        code: i32,
+        msg: String,
+        position: Option<Position>,
+    },
+    /// The output buffer was too small to hold the result.
+    ///
+    /// `required` is the size the buffer needs, when it is known.
+    OutputTooSmall { required: Option<u64> },
+    /// Processing was stopped through a [`CancelToken`](crate::CancelToken).
+    ///
+    /// The output holds the file header and the first `windows` target windows, each of
+    /// them complete. Nothing of a later window has been written. The streaming functions
+    /// flush their writer before returning this error.
+    ///
+    /// The in-memory functions such as [`encode_with`](crate::encode_with) and
+    /// [`decode_with`](crate::decode_with) drop the partial output, so there `windows`
+    /// only tells how far they got.
+    Cancelled {
+        windows: u64,
+        position: Option<Position>,
+    },
+}
+
+/// Result type used throughout this crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Converts a libxdelta3 return code into an error.
+    ///
+    /// `msg` is the `msg` field of the stream, which may be null.
+    pub(crate) fn from_code(code: c_int, msg: *const c_char, position: Option<Position>) -> Self {
+        let msg = if !msg.is_null() {
+            unsafe { CStr::from_ptr(msg) }
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            strerror(code)
+        };
+
+        use binding::xd3_rvalues::*;
+        match code {
+            c if c == XD3_INVALID_INPUT as c_int && msg == CHECKSUM_MISMATCH_MSG => {
+                Error::ChecksumMismatch { msg, position }
+            }
+            c if c == XD3_INVALID_INPUT as c_int => Error::InvalidInput { msg, position },
+            c if c == XD3_NOSECOND as c_int => Error::NoSecondary { msg, position },
+            c if c == XD3_TOOFARBACK as c_int => Error::TooFarBack { msg, position },
+            c if c == XD3_INVALID as c_int => Error::InvalidConfig { msg },
+            libc::ENOSPC => Error::OutputTooSmall { required: None },
+            code => Error::Internal {
+                code,
+                msg,
+                position,
+            },
+        }
+    }
+
+    /// Returns the message reported by libxdelta3, if any.
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            Error::InvalidInput { msg, .. }
+            | Error::ChecksumMismatch { msg, .. }
+            | Error::NoSecondary { msg, .. }
+            | Error::TooFarBack { msg, .. }
+            | Error::InvalidConfig { msg }
+            | Error::Internal { msg, .. } => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Returns the position at which the error was detected, if known.
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Error::InvalidInput { position, .. }
+            | Error::ChecksumMismatch { position, .. }
+            | Error::NoSecondary { position, .. }
+            | Error::TooFarBack { position, .. }
+            | Error::Internal { position, .. }
+            | Error::Cancelled { position, .. } => *position,
+            _ => None,
+        }
+    }
+}
+
+fn strerror(code: c_int) -> String {
+    let s = unsafe { binding::xd3_strerror(code) };
+    if s.is_null() {
+        format!("error code {}", code)
+    } else {
+        unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Input(e) => write!(f, "error reading input: {}", e)?,
+            Error::Source(e) => write!(f, "error reading source: {}", e)?,
+            Error::Output(e) => write!(f, "error writing output: {}", e)?,
+            Error::InvalidInput { msg, .. } => write!(f, "invalid input: {}", msg)?,
+            Error::ChecksumMismatch { msg, .. } => f.write_str(msg)?,
+            Error::NoSecondary { msg, .. } => {
+                write!(f, "secondary compressor unavailable: {}", msg)?
+            }
+            Error::TooFarBack { msg, .. } => write!(f, "source copy too far back: {}", msg)?,
+            Error::InvalidConfig { msg } => write!(f, "invalid configuration: {}", msg)?,
+            Error::Internal { code, msg, .. } => write!(f, "xdelta3 error {}: {}", code, msg)?,
+            Error::OutputTooSmall { required: None } => write!(f, "output buffer too small")?,
+            Error::OutputTooSmall {
+                required: Some(required),
+            } => write!(f, "output buffer too small, {} bytes needed", required)?,
+            Error::Cancelled { windows, .. } => {
+                write!(f, "cancelled after {} complete windows", windows)?
+            }
+        }
+        if let Some(pos) = self.position() {
+            write!(f, " (window {}, offset {})", pos.window, pos.offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Input(e) | Error::Source(e) | Error::Output(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    /// Unwraps I/O errors, and wraps the other errors in an `io::Error`.
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Input(e) | Error::Source(e) | Error::Output(e) => e,
+            Error::InvalidInput { .. } | Error::ChecksumMismatch { .. } => {
+                io::Error::new(io::ErrorKind::InvalidData, err)
+            }
+            err => io::Error::other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode, encode_with, EncoderConfig};
+
+    /// Offset of the Adler-32 checksum of the first window of `patch`, a patch with a
+    /// source and without an application header or secondary compression.
+    fn checksum_offset(patch: &[u8]) -> usize {
+        let skip_varint = |pos: &mut usize| {
+            while patch[*pos] & 0x80 != 0 {
+                *pos += 1;
+            }
+            *pos += 1;
+        };
+        assert_eq!(patch[4], 0, "unexpected header indicator");
+        let win_ind = patch[5];
+        assert_eq!(
+            win_ind & 0x05,
+            0x05,
+            "window without a source or a checksum"
+        );
+        let mut pos = 6;
+        // Copy window length and offset, encoding length, target length.
+        for _ in 0..4 {
+            skip_varint(&mut pos);
+        }
+        // Delta indicator, then the lengths of the data, instruction and address sections.
+        pos += 1;
+        for _ in 0..3 {
+            skip_varint(&mut pos);
+        }
+        pos
+    }
+
+    #[test]
+    fn checksum_mismatch_message() {
+        let source: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut input = source.clone();
+        input[5000..5100].iter_mut().for_each(|b| *b = 0);
+        let config = EncoderConfig::new().checksum(true);
+        let mut patch = encode_with(&input, &source, &config).expect("failed to encode");
+
+        let pos = checksum_offset(&patch);
+        patch[pos] ^= 0xff;
+        match decode(&patch, &source) {
+            Err(e @ Error::ChecksumMismatch { .. }) => {
+                assert_eq!(e.message(), Some(CHECKSUM_MISMATCH_MSG));
+                assert!(e.to_string().starts_with(CHECKSUM_MISMATCH_MSG));
+            }
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+}
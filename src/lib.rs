@@ -13,13 +13,43 @@
 
 extern crate libc;
 
-use libc::c_uint;
+use std::convert::TryFrom;
 
+mod appheader;
+mod cancel;
+mod codec;
+mod config;
+mod error;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod progress;
+mod source;
+mod srcbuf;
+mod stats;
 #[cfg(feature = "stream")]
 pub mod stream;
+pub mod sync;
+mod vcdiff;
+mod xd3;
+
+use config::Settings;
+use xd3::{Mode, Xd3Stream, XD3_DEFAULT_WINSIZE};
+
+pub use appheader::CliAppHeader;
+pub use cancel::CancelToken;
+pub use codec::{Decoder, Encoder, Event};
+pub use config::{
+    DecoderConfig, EncoderConfig, Matcher, MatcherParams, SecondaryCompression, SecondarySections,
+};
+pub use error::{Error, Position, Result};
+#[cfg(feature = "mmap")]
+pub use mmap::MmapSource;
+pub use progress::Progress;
+pub use source::Source;
+pub use stats::{InstructionStats, Stats};
 
-#[allow(dead_code)]
 mod binding {
+    #![allow(dead_code)]
     #![allow(non_upper_case_globals)]
     #![allow(non_camel_case_types)]
     #![allow(non_snake_case)]
@@ -54,30 +84,21 @@ mod binding {
 /// You might notice the generated patch data is larger than both orginal data and the updated data.
 /// But don't worry, if your data is large enough and kind of similar between each other (usually the case
 /// for software updates or ROM patches), the patch data should be only a fraction of your updated file.
-pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
-    unsafe {
-        let input_len = input.len() as c_uint;
-        let src_len = src.len() as c_uint;
-        let estimated_out_len = (input_len + src_len) * 2;
-        let mut avail_output = 0 as c_uint;
-        let mut output = Vec::with_capacity(estimated_out_len as usize);
-        let result = binding::xd3_encode_memory(
-            input.as_ptr(),
-            input_len,
-            src.as_ptr(),
-            src_len,
-            output.as_mut_ptr(),
-            &mut avail_output,
-            estimated_out_len,
-            0,
-        );
-        if result == 0 {
-            output.set_len(avail_output as usize);
-            Some(output)
-        } else {
-            None
-        }
-    }
+///
+/// An [`Error`] is returned if xdelta3 fails to generate the patch.
+pub fn encode(input: &[u8], src: &[u8]) -> Result<Vec<u8>> {
+    encode_with(input, src, &EncoderConfig::default())
+}
+
+/// Function to generate the difference data with custom settings
+///
+/// This works like [`encode`], with the compression level, matcher and buffer sizes
+/// taken from `config`.
+pub fn encode_with(input: &[u8], src: &[u8], config: &EncoderConfig) -> Result<Vec<u8>> {
+    let mut settings = config.settings()?;
+    let mut output = Vec::new();
+    process_memory(Mode::Encode, &mut settings, input, src, &mut output)?;
+    Ok(output)
 }
 
 /// Function to decode the difference data
@@ -93,6 +114,8 @@ pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
 /// for the file integrity after doing the decode to prevent from creating
 /// potentially corrupted files
 ///
+/// If `input` is not a valid patch, [`Error::InvalidInput`] is returned.
+///
 /// Here is a basic example to show how to use this function:
 /// ```
 /// extern crate xdelta3;
@@ -103,28 +126,435 @@ pub fn encode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
 ///     assert_eq!(result.unwrap().as_slice(), &[1, 2, 3, 4, 5, 6, 7]);
 /// }
 /// ```
-pub fn decode(input: &[u8], src: &[u8]) -> Option<Vec<u8>> {
-    unsafe {
-        let input_len = input.len() as c_uint;
-        let src_len = src.len() as c_uint;
-        let estimated_out_len = (input_len + src_len) * 2;
-        let mut avail_output = 0 as c_uint;
-        let mut output = Vec::with_capacity(estimated_out_len as usize);
-        let result = binding::xd3_decode_memory(
-            input.as_ptr(),
-            input_len,
-            src.as_ptr(),
-            src_len,
-            output.as_mut_ptr(),
-            &mut avail_output,
-            estimated_out_len,
-            0,
-        );
-        if result == 0 {
-            output.set_len(avail_output as usize);
-            Some(output)
-        } else {
-            None
+pub fn decode(input: &[u8], src: &[u8]) -> Result<Vec<u8>> {
+    decode_with(input, src, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data with custom settings
+///
+/// This works like [`decode`], with the settings taken from `config`.
+pub fn decode_with(input: &[u8], src: &[u8], config: &DecoderConfig) -> Result<Vec<u8>> {
+    let mut settings = config.settings()?;
+    // The window headers tell us the size of the output, but they come from the patch, so
+    // the claim is only trusted up to a bound tied to the lengths of the patch and the
+    // source. Past that, or if the allocation fails, the output grows as it is produced.
+    let bound = src
+        .len()
+        .saturating_add(input.len().saturating_mul(MAX_DECODE_EXPANSION));
+    let target_len = vcdiff::target_len(input);
+    settings.total_target_len = settings.total_target_len.or(target_len);
+    let out_len = target_len
+        .and_then(|len| usize::try_from(len).ok())
+        .unwrap_or(0)
+        .min(bound);
+    let mut output = Vec::new();
+    let _ = output.try_reserve_exact(out_len);
+    process_memory(Mode::Decode, &mut settings, input, src, &mut output)?;
+    Ok(output)
+}
+
+/// Function to generate the difference data into a buffer provided by the caller
+///
+/// This works like [`encode`], but writes the patch to the start of `out` and returns its
+/// length. Nothing is allocated on the Rust side, so `out` can be reused across calls.
+///
+/// If the patch does not fit, [`Error::OutputTooSmall`] is returned with the size `out`
+/// needs. The contents of `out` are unspecified in that case.
+///
+/// ```
+/// use xdelta3::{encode_into, Error};
+///
+/// let mut out = [0u8; 64];
+/// let len = encode_into(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7], &mut out).unwrap();
+/// assert_eq!(&out[..len], &[214, 195, 196, 0, 0, 0, 13, 7, 0, 7, 1, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+///
+/// let result = encode_into(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7], &mut out[..4]);
+/// assert!(matches!(result, Err(Error::OutputTooSmall { required: Some(20) })));
+/// ```
+pub fn encode_into(input: &[u8], src: &[u8], out: &mut [u8]) -> Result<usize> {
+    encode_into_with(input, src, out, &EncoderConfig::default())
+}
+
+/// Function to generate the difference data into a buffer provided by the caller, with
+/// custom settings
+///
+/// This works like [`encode_into`], with the settings taken from `config`.
+pub fn encode_into_with(
+    input: &[u8],
+    src: &[u8],
+    out: &mut [u8],
+    config: &EncoderConfig,
+) -> Result<usize> {
+    let mut settings = config.settings()?;
+    let mut output = SliceOutput::new(out);
+    process_memory(Mode::Encode, &mut settings, input, src, &mut output)?;
+    output.finish()
+}
+
+/// Function to decode the difference data into a buffer provided by the caller
+///
+/// This works like [`decode`], but writes the patched data to the start of `out` and
+/// returns its length. Nothing is allocated on the Rust side, so `out` can be reused
+/// across calls.
+///
+/// If the result does not fit, [`Error::OutputTooSmall`] is returned with the size `out`
+/// needs. The size is read from the window headers of the patch, so in most cases nothing
+/// is decoded before the error is returned. The contents of `out` are unspecified in that
+/// case.
+pub fn decode_into(input: &[u8], src: &[u8], out: &mut [u8]) -> Result<usize> {
+    decode_into_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data into a buffer provided by the caller, with
+/// custom settings
+///
+/// This works like [`decode_into`], with the settings taken from `config`.
+pub fn decode_into_with(
+    input: &[u8],
+    src: &[u8],
+    out: &mut [u8],
+    config: &DecoderConfig,
+) -> Result<usize> {
+    let mut settings = config.settings()?;
+    let target_len = vcdiff::target_len(input);
+    settings.total_target_len = settings.total_target_len.or(target_len);
+    if let Some(len) = target_len {
+        if len > out.len() as u64 {
+            return Err(Error::OutputTooSmall {
+                required: Some(len),
+            });
+        }
+    }
+    let mut output = SliceOutput::new(out);
+    process_memory(Mode::Decode, &mut settings, input, src, &mut output)?;
+    output.finish()
+}
+
+/// Function to compress data without a source
+///
+/// The result is a VCDIFF patch against an empty source: xdelta3 only finds matches
+/// within `input` itself, so it works like a general-purpose compressor. Such a patch
+/// is applied with [`decompress`], and can be read by any VCDIFF decoder.
+///
+/// ```
+/// use xdelta3::{compress, decompress};
+///
+/// let data = b"abcabcabcabcabcabcabcabc";
+/// let patch = compress(data).unwrap();
+/// assert_eq!(decompress(&patch).unwrap(), data);
+/// ```
+pub fn compress(input: &[u8]) -> Result<Vec<u8>> {
+    compress_with(input, &EncoderConfig::default())
+}
+
+/// Function to compress data without a source, with custom settings
+///
+/// This works like [`compress`], with the settings taken from `config`.
+pub fn compress_with(input: &[u8], config: &EncoderConfig) -> Result<Vec<u8>> {
+    encode_with(input, &[], config)
+}
+
+/// Function to decompress data made by [`compress`]
+///
+/// If `input` copies from a source, [`Error::InvalidInput`] is returned; use [`decode`]
+/// for such patches.
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>> {
+    decompress_with(input, &DecoderConfig::default())
+}
+
+/// Function to decompress data made by [`compress`], with custom settings
+///
+/// This works like [`decompress`], with the settings taken from `config`.
+pub fn decompress_with(input: &[u8], config: &DecoderConfig) -> Result<Vec<u8>> {
+    decode_with(input, &[], config)
+}
+
+/// Function to read the application header of a patch
+///
+/// Returns the bytes set with [`EncoderConfig::app_header`], or `None` if the patch has
+/// no application header. Only the header of the patch is decoded, so no source is needed.
+///
+/// ```
+/// use xdelta3::{app_header, encode_with, EncoderConfig};
+///
+/// let config = EncoderConfig::new().app_header("v2");
+/// let patch = encode_with(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7], &config).unwrap();
+/// assert_eq!(app_header(&patch).unwrap().unwrap(), b"v2");
+/// ```
+pub fn app_header(patch: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
+    let mut stream = Xd3Stream::new();
+    stream.config(&mut cfg)?;
+
+    let chunk_size = stream.winsize as usize;
+    let mut pos = 0;
+    loop {
+        use binding::xd3_rvalues::*;
+        match stream.step(Mode::Decode)? {
+            XD3_GOTHEADER => return Ok(stream.app_header().map(<[u8]>::to_vec)),
+            XD3_INPUT => {
+                if pos == patch.len() {
+                    return Err(Error::InvalidInput {
+                        msg: "patch ends inside its header".to_owned(),
+                        position: Some(stream.position()),
+                    });
+                }
+                let end = patch.len().min(pos + chunk_size);
+                stream.avail_input(&patch[pos..end]);
+                pos = end;
+            }
+            _ => {
+                // nothing else happens before the header is complete
+            }
         }
     }
 }
+
+/// Function to read the length of the target from the window headers of a patch
+///
+/// Returns `None` if the headers cannot be read, for example when `patch` is truncated.
+/// The length can be passed to [`DecoderConfig::total_target_len`] so that the streaming
+/// functions report it as progress.
+///
+/// ```
+/// let patch = xdelta3::encode(&[1, 2, 3, 4, 5, 6, 7], &[1, 2, 4, 4, 7, 6, 7]).unwrap();
+/// assert_eq!(xdelta3::target_len(&patch), Some(7));
+/// ```
+pub fn target_len(patch: &[u8]) -> Option<u64> {
+    vcdiff::target_len(patch)
+}
+
+// Output reserved up front by `decode_with` is at most this many bytes per byte of patch,
+// on top of the length of the source.
+const MAX_DECODE_EXPANSION: usize = 64;
+
+// Source blocks are handed to xdelta3 straight from the `src` slice. A block length
+// must fit in a `usize_t`, so larger sources are split into blocks of this size.
+// The tests use small limits, so that they cover several blocks without gigabytes of data.
+#[cfg(not(test))]
+const MAX_MEMORY_BLKSIZE: usize = 1 << 30;
+#[cfg(test)]
+const MAX_MEMORY_BLKSIZE: usize = 1 << 14;
+// Upper bound on the source window searched by the encoder, which sizes its hash table.
+#[cfg(not(test))]
+const MAX_MEMORY_SRCWINSZ: usize = 1 << 30;
+#[cfg(test)]
+const MAX_MEMORY_SRCWINSZ: usize = 1 << 16;
+
+/// Runs a whole encode or decode over in-memory buffers, appending the result to `out`.
+///
+/// This does what `xd3_encode_memory`/`xd3_decode_memory` do, but feeds the input window
+/// by window and the source block by block, so the lengths are not limited to a `usize_t`.
+///
+/// The stream lives on the stack, so nothing is allocated on the Rust side beyond what
+/// `out` does.
+fn process_memory<O: MemoryOutput>(
+    mode: Mode,
+    settings: &mut Settings,
+    input: &[u8],
+    src: &[u8],
+    out: &mut O,
+) -> Result<()> {
+    let cfg = &mut settings.xd3;
+    if mode == Mode::Encode && cfg.winsize == 0 {
+        let winsize = input.len().min(XD3_DEFAULT_WINSIZE);
+        cfg.winsize = winsize as u32;
+        if cfg.sprevsz == 0 {
+            cfg.sprevsz = winsize.next_power_of_two() as u32;
+        }
+    }
+
+    let mut source: binding::xd3_source = unsafe { std::mem::zeroed() };
+    let blksize = src.len().next_power_of_two().min(MAX_MEMORY_BLKSIZE);
+    source.blksize = blksize as u32;
+    source.max_winsize = src.len().min(MAX_MEMORY_SRCWINSZ) as u64;
+
+    let mut raw_stream: binding::xd3_stream = unsafe { std::mem::zeroed() };
+    let mut stream = Xd3Stream::in_place(&mut raw_stream);
+    stream.config(cfg)?;
+    if let Some(header) = settings.app_header {
+        unsafe { stream.set_app_header(header) };
+    }
+    if !src.is_empty() {
+        unsafe { stream.set_source(&mut source, Some(src.len() as u64))? };
+    }
+
+    // xd3_set_flags
+    stream.flags |= binding::xd3_flags::XD3_FLUSH as i32;
+
+    let chunk_size = stream.winsize as usize;
+    let mut pos = input.len().min(chunk_size);
+    stream.avail_input(&input[..pos]);
+
+    let total_target_len = match mode {
+        Mode::Encode => Some(input.len() as u64),
+        Mode::Decode => settings.total_target_len,
+    };
+    let out_start = out.len();
+    let mut source_bytes = 0u64;
+    let mut windows = 0u64;
+    let check_cancelled = |stream: &Xd3Stream<_>, windows| match settings.cancel {
+        Some(token) if token.is_cancelled() => Err(Error::Cancelled {
+            windows,
+            position: Some(stream.position()),
+        }),
+        _ => Ok(()),
+    };
+    let report = |stream: &Xd3Stream<_>, out: &O, source_bytes, window_finished| {
+        if let Some(observer) = settings.progress {
+            let output_bytes = (out.len() - out_start) as u64;
+            observer.report(&Progress::new(
+                stream,
+                mode,
+                output_bytes,
+                source_bytes,
+                total_target_len,
+                window_finished,
+            ));
+        }
+    };
+
+    loop {
+        use binding::xd3_rvalues::*;
+        match stream.step(mode)? {
+            XD3_INPUT => {
+                if pos == input.len() {
+                    break;
+                }
+                check_cancelled(&stream, windows)?;
+                let end = input.len().min(pos + chunk_size);
+                stream.avail_input(&input[pos..end]);
+                pos = end;
+            }
+            XD3_OUTPUT => {
+                out.write(stream.output());
+                stream.consume_output();
+            }
+            XD3_GETSRCBLK => {
+                let blkno = source.getblkno;
+                let start = usize::try_from(blkno)
+                    .ok()
+                    .and_then(|n| n.checked_mul(blksize))
+                    .filter(|&start| start < src.len())
+                    .ok_or_else(|| Error::InvalidInput {
+                        msg: format!("patch copies from source block {} past the end", blkno),
+                        position: Some(stream.position()),
+                    })?;
+                let block = &src[start..src.len().min(start + blksize)];
+
+                source.curblkno = blkno;
+                source.curblk = block.as_ptr();
+                source.onblk = block.len() as u32;
+                source_bytes += block.len() as u64;
+            }
+            XD3_GOTHEADER | XD3_WINSTART => report(&stream, out, source_bytes, false),
+            XD3_WINFINISH => {
+                windows += 1;
+                report(&stream, out, source_bytes, true);
+                check_cancelled(&stream, windows)?;
+            }
+            _ => {
+                // step() returns nothing else
+            }
+        }
+    }
+
+    stream.close(mode)
+}
+
+/// Where [`process_memory`] puts its output.
+trait MemoryOutput {
+    fn write(&mut self, data: &[u8]);
+    /// Length of the output so far.
+    fn len(&self) -> usize;
+}
+
+impl MemoryOutput for Vec<u8> {
+    fn write(&mut self, data: &[u8]) {
+        self.extend_from_slice(data);
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+}
+
+/// A buffer provided by the caller. Output that does not fit is only counted, so the
+/// size needed can be reported.
+struct SliceOutput<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceOutput<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// Returns the length of the output, or [`Error::OutputTooSmall`] if it did not fit.
+    fn finish(self) -> Result<usize> {
+        if self.len > self.buf.len() {
+            return Err(Error::OutputTooSmall {
+                required: Some(self.len as u64),
+            });
+        }
+        Ok(self.len)
+    }
+}
+
+impl MemoryOutput for SliceOutput<'_> {
+    fn write(&mut self, data: &[u8]) {
+        let end = self.len + data.len();
+        if end <= self.buf.len() {
+            self.buf[self.len..end].copy_from_slice(data);
+        }
+        self.len = end;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes that do not repeat within a block, so that only copies from the source
+    /// make the patch small.
+    pub(crate) fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn memory_blocks_and_windows() {
+        let src = noise(5 * MAX_MEMORY_BLKSIZE + 100, 1);
+        let mut input = src.clone();
+        input[1000..1100].copy_from_slice(&noise(100, 2));
+        input.extend_from_slice(&noise(3000, 3));
+
+        // Several windows of input, each copying from a source of several blocks.
+        let config = EncoderConfig::new().window_size(1 << 14);
+        let patch = encode_with(&input, &src, &config).expect("failed to encode");
+        assert!(patch.len() < input.len() / 4);
+        assert_eq!(decode(&patch, &src).expect("failed to decode"), input);
+    }
+
+    #[test]
+    fn memory_source_too_short() {
+        let src = noise(4 * MAX_MEMORY_BLKSIZE, 1);
+        let patch = encode(&src, &src).expect("failed to encode");
+        let result = decode(&patch, &src[..MAX_MEMORY_BLKSIZE]);
+        assert!(
+            matches!(result, Err(Error::InvalidInput { .. })),
+            "{:?}",
+            result
+        );
+    }
+}
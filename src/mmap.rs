@@ -0,0 +1,137 @@
+//! Memory-mapped file source.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::task::{Context, Poll};
+
+use memmap2::Mmap;
+
+use super::source::{read_slice, BlockSource, Source};
+
+/// A source file mapped into memory.
+///
+/// The streaming functions that take a `MmapSource` hand xdelta3 pointers straight into
+/// the map, so source blocks are never copied and any block can be read at any time.
+///
+/// The file must not be modified while it is mapped. The `*_mmap` streaming functions
+/// compare the size of the file with the map once, when they start, and report a file
+/// that is already shorter as an [`Error::Source`](crate::Error::Source) error. This check
+/// is best-effort: it costs no system call per block, but a file truncated after it, or
+/// between it and the first read, makes the operating system kill the process with
+/// `SIGBUS` when xdelta3 reads past the new end, and nothing in this crate can prevent it.
+/// Only map files that nothing else writes to; read other files through the [`Source`]
+/// implementation of [`File`] instead.
+pub struct MmapSource {
+    file: File,
+    /// `None` for an empty file, which cannot be mapped.
+    map: Option<Mmap>,
+}
+
+impl MmapSource {
+    /// Opens and maps the file at `path`.
+    ///
+    /// Truncating the file while it is mapped can kill the process with `SIGBUS`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    /// Maps `file`, which must be open for reading.
+    ///
+    /// Truncating the file while it is mapped can kill the process with `SIGBUS`.
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        let map = if len == 0 {
+            None
+        } else {
+            Some(unsafe { Mmap::map(&file)? })
+        };
+        Ok(Self { file, map })
+    }
+
+    /// Length of the mapped file.
+    pub fn len(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+
+    /// Returns true if the mapped file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the mapped file.
+    fn data(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or(&[])
+    }
+
+    /// Checks that the file has not been truncated since it was mapped.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.file.metadata()?.len() < self.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "source file was truncated while mapped",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Copies blocks out of the map. The `*_mmap` streaming functions hand them to xdelta3
+/// without copying.
+impl Source for &MmapSource {
+    fn total_len(&self) -> Option<u64> {
+        Some(self.len())
+    }
+
+    fn poll_block(
+        &mut self,
+        _cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let pos = blkno * buf.len() as u64;
+        Poll::Ready(Ok(read_slice(self.data(), pos, buf)))
+    }
+}
+
+impl Source for MmapSource {
+    fn total_len(&self) -> Option<u64> {
+        Some(self.len())
+    }
+
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Source::poll_block(&mut &*self, cx, blkno, buf)
+    }
+}
+
+impl BlockSource for &MmapSource {
+    fn total_len(&self) -> Option<u64> {
+        Some(self.len())
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    fn mapped(&self) -> Option<&[u8]> {
+        Some(self.data())
+    }
+
+    fn check(&self) -> io::Result<()> {
+        MmapSource::check(self)
+    }
+
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Source::poll_block(self, cx, blkno, buf)
+    }
+}
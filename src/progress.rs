@@ -0,0 +1,82 @@
+//! Progress reporting for long encodes and decodes.
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::binding;
+use super::xd3::Mode;
+
+/// Progress of an encode or decode, passed to the observer set with
+/// [`EncoderConfig::progress`](crate::EncoderConfig::progress) or
+/// [`DecoderConfig::progress`](crate::DecoderConfig::progress).
+///
+/// The fraction done is `output_bytes` over `total_target_len`, when the length of the
+/// whole target is known. Otherwise it is best worked out from `input_bytes` and the
+/// length of the input, when the caller knows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Input consumed so far: the updated data when encoding, the patch when decoding.
+    pub input_bytes: u64,
+    /// Output produced so far.
+    pub output_bytes: u64,
+    /// Source data read so far. A block read again after it was evicted counts again.
+    pub source_bytes: u64,
+    /// Number of the current window, starting from 0.
+    pub window: u64,
+    /// Length of the target window, from its window header. Only known to the decoder once
+    /// the window is finished.
+    pub target_window_len: Option<u64>,
+    /// Length of the whole target, if it is known up front: the input of the in-memory
+    /// encoder, the sum of the window headers for the in-memory decoder, or the length set
+    /// with [`DecoderConfig::total_target_len`](crate::DecoderConfig::total_target_len).
+    pub total_target_len: Option<u64>,
+}
+
+impl Progress {
+    /// Progress of `stream` at an `XD3_GOTHEADER`, `XD3_WINSTART` or `XD3_WINFINISH` event.
+    pub(crate) fn new(
+        stream: &binding::xd3_stream,
+        mode: Mode,
+        output_bytes: u64,
+        source_bytes: u64,
+        total_target_len: Option<u64>,
+        window_finished: bool,
+    ) -> Self {
+        let target_window_len = if mode == Mode::Decode && window_finished {
+            Some(u64::from(stream.dec_tgtlen))
+        } else {
+            None
+        };
+        Self {
+            input_bytes: stream.total_in,
+            output_bytes,
+            source_bytes,
+            window: stream.current_window,
+            target_window_len,
+            total_target_len,
+        }
+    }
+}
+
+/// Progress callback shared by the clones of a configuration.
+#[derive(Clone)]
+pub(crate) struct Observer(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl Observer {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn report(&self, progress: &Progress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
@@ -0,0 +1,402 @@
+//! Sources of the original data for the streaming functions.
+//!
+//! The streaming front ends read the source through [`BlockSource`], a block at a time,
+//! into the cache kept by [`SrcBuffer`]. Any [`Source`] can be used through [`Blocks`].
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::task::{ready, Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use super::codec::Codec;
+use super::srcbuf::SrcBuffer;
+use super::{Error, Position, Result};
+
+/// The original data a patch is made from or applied to, read a block at a time.
+///
+/// The streaming functions ask for block `n` with a buffer of the block size, and expect
+/// the `buf.len()` bytes at offset `n * buf.len()` of the source. Implement this to read
+/// the source from somewhere the crate does not know about, such as an object store, an
+/// encrypted container or several files put together.
+///
+/// The crate implements it for `&[u8]`, `Vec<u8>` and [`File`], for the memory-mapped
+/// [`MmapSource`](crate::MmapSource), and for readers wrapped in `ReadSource` or
+/// `SeekSource` from the [`stream`](crate::stream) or [`sync`](crate::sync) module.
+///
+/// The blocking functions in [`sync`](crate::sync) need a source whose `poll_block` never
+/// returns `Poll::Pending`: they have no way to wait, so a `Poll::Pending` ends them with
+/// an [`Error::Source`](crate::Error::Source) of kind
+/// [`WouldBlock`](std::io::ErrorKind::WouldBlock).
+///
+/// ```
+/// use std::io;
+/// use std::task::{Context, Poll};
+/// use xdelta3::Source;
+///
+/// /// A source of `len` zero bytes.
+/// struct Zeros {
+///     len: u64,
+/// }
+///
+/// impl Source for Zeros {
+///     fn total_len(&self) -> Option<u64> {
+///         Some(self.len)
+///     }
+///
+///     fn poll_block(
+///         &mut self,
+///         _cx: &mut Context<'_>,
+///         blkno: u64,
+///         buf: &mut [u8],
+///     ) -> Poll<io::Result<usize>> {
+///         let start = (blkno * buf.len() as u64).min(self.len);
+///         let len = (self.len - start).min(buf.len() as u64) as usize;
+///         buf[..len].iter_mut().for_each(|b| *b = 0);
+///         Poll::Ready(Ok(len))
+///     }
+/// }
+///
+/// let input = [0u8; 1000];
+/// let mut patch = Vec::new();
+/// xdelta3::sync::encode_source(&input[..], Zeros { len: 1000 }, &mut patch).unwrap();
+/// assert_eq!(xdelta3::decode(&patch, &[0u8; 1000]).unwrap(), &input[..]);
+/// ```
+pub trait Source {
+    /// Total length of the source, if it is known before the source is read.
+    ///
+    /// When it is not known, the source ends at the first block shorter than the block
+    /// size.
+    fn total_len(&self) -> Option<u64>;
+
+    /// Whether blocks can be read in any order. True by default.
+    ///
+    /// A source that cannot seek is read front to back, each block once, so a patch that
+    /// copies from a block that has left the source window cannot be applied.
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    /// Reads block `blkno` into `buf`, and returns the length read.
+    ///
+    /// The whole buffer must be filled, except for the last block of the source, which
+    /// may be shorter. A block past the end of the source has length zero.
+    ///
+    /// After `Poll::Pending`, the same block is asked for again with the same buffer,
+    /// whose contents are kept, so an implementation can fill it over several polls.
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>>;
+}
+
+impl<S: Source + ?Sized> Source for &mut S {
+    fn total_len(&self) -> Option<u64> {
+        (**self).total_len()
+    }
+
+    fn can_seek(&self) -> bool {
+        (**self).can_seek()
+    }
+
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        (**self).poll_block(cx, blkno, buf)
+    }
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn total_len(&self) -> Option<u64> {
+        (**self).total_len()
+    }
+
+    fn can_seek(&self) -> bool {
+        (**self).can_seek()
+    }
+
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        (**self).poll_block(cx, blkno, buf)
+    }
+}
+
+impl Source for &[u8] {
+    fn total_len(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+
+    fn poll_block(
+        &mut self,
+        _cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(read_slice(self, blkno * buf.len() as u64, buf)))
+    }
+}
+
+impl Source for Vec<u8> {
+    fn total_len(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+
+    fn poll_block(
+        &mut self,
+        _cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(read_slice(self, blkno * buf.len() as u64, buf)))
+    }
+}
+
+/// The whole file, from its start. Reads block the thread, also in the async functions.
+///
+/// A file that is not a regular file, such as a pipe or a character device, has no known
+/// length and cannot seek, so it is read front to back from its current position.
+///
+/// `total_len` and `can_seek` look at the metadata of the file. The streaming functions
+/// ask for them once, and each block read only seeks.
+impl Source for File {
+    fn total_len(&self) -> Option<u64> {
+        let metadata = self.metadata().ok()?;
+        if metadata.is_file() {
+            Some(metadata.len())
+        } else {
+            None
+        }
+    }
+
+    fn can_seek(&self) -> bool {
+        self.metadata().is_ok_and(|metadata| metadata.is_file())
+    }
+
+    fn poll_block(
+        &mut self,
+        _cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let start = blkno * buf.len() as u64;
+        let mut filled = 0;
+        poll_fill_block(&mut filled, buf, |offset, buf| {
+            // A file that cannot seek is read front to back, so the read goes on from
+            // where the last one stopped.
+            if let Err(e) = self.seek(SeekFrom::Start(start + offset as u64)) {
+                if self.can_seek() {
+                    return Poll::Ready(Err(e));
+                }
+            }
+            Poll::Ready(self.read(buf))
+        })
+    }
+}
+
+/// Reads into `buf` until it is full or `read` returns zero bytes, and returns the length
+/// read. Interrupted reads are retried.
+///
+/// `read` is passed the offset in `buf` to read at. `filled` keeps that offset across
+/// `Poll::Pending`, and is reset once the block is complete.
+pub(crate) fn poll_fill_block<F>(
+    filled: &mut usize,
+    buf: &mut [u8],
+    mut read: F,
+) -> Poll<io::Result<usize>>
+where
+    F: FnMut(usize, &mut [u8]) -> Poll<io::Result<usize>>,
+{
+    while *filled < buf.len() {
+        match ready!(read(*filled, &mut buf[*filled..])) {
+            Ok(0) => break,
+            Ok(len) => *filled += len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+    }
+    Poll::Ready(Ok(std::mem::take(filled)))
+}
+
+/// Error for a forward-only source asked for a block out of order.
+pub(crate) fn out_of_order(blkno: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("source block {} asked for out of order", blkno),
+    )
+}
+
+/// Copies from offset `pos` of `data` into `buf`, like a read from a file holding `data`.
+pub(crate) fn read_slice(data: &[u8], pos: u64, buf: &mut [u8]) -> usize {
+    let start = pos.min(data.len() as u64) as usize;
+    let len = buf.len().min(data.len() - start);
+    buf[..len].copy_from_slice(&data[start..start + len]);
+    len
+}
+
+/// What the front ends need from a source.
+///
+/// This is [`Source`] with one addition the public trait cannot offer safely: a source
+/// already in memory at a fixed address hands its blocks to xdelta3 without copying.
+pub(crate) trait BlockSource {
+    fn total_len(&self) -> Option<u64>;
+
+    fn can_seek(&self) -> bool;
+
+    /// Returns the whole source if it is mapped, so blocks need not be copied.
+    ///
+    /// The data must stay at the same address for as long as the source is alive.
+    fn mapped(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Checks that the source is still usable, once, before it is attached.
+    fn check(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>>;
+}
+
+/// Reads a [`Source`] through [`BlockSource`], copying each block.
+///
+/// The length of the source and whether it can seek are asked for once, when it is
+/// wrapped, since they can cost a system call each.
+pub(crate) struct Blocks<S> {
+    src: S,
+    total_len: Option<u64>,
+    can_seek: bool,
+}
+
+impl<S: Source> Blocks<S> {
+    pub(crate) fn new(src: S) -> Self {
+        Self {
+            total_len: src.total_len(),
+            can_seek: src.can_seek(),
+            src,
+        }
+    }
+}
+
+impl<S: Source> BlockSource for Blocks<S> {
+    fn total_len(&self) -> Option<u64> {
+        self.total_len
+    }
+
+    fn can_seek(&self) -> bool {
+        self.can_seek
+    }
+
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.src.poll_block(cx, blkno, buf)
+    }
+}
+
+/// Reads source blocks from `src` until block `idx` is in `buf` or the source ends.
+fn poll_fill_source<S: BlockSource>(
+    cx: &mut Context<'_>,
+    buf: &mut SrcBuffer,
+    src: &mut S,
+    idx: usize,
+    position: Option<Position>,
+) -> Poll<Result<()>> {
+    while buf.needs_block(idx) {
+        let blkno = buf
+            .next_read(idx, src.can_seek())
+            .ok_or_else(|| buf.evicted(idx, position))?;
+        let block = buf.next_block(blkno);
+        let block_len = block.len();
+        let read_len = ready!(src.poll_block(cx, blkno as u64, block)).map_err(Error::Source)?;
+        if read_len > block_len {
+            return Poll::Ready(Err(Error::Source(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "source returned more than a block",
+            ))));
+        }
+        buf.push_block(blkno, read_len);
+    }
+    Poll::Ready(Ok(()))
+}
+
+/// Reads the first source window, unless the source length is already known, and
+/// attaches the source to the stream.
+pub(crate) fn poll_start_source<S: BlockSource>(
+    cx: &mut Context<'_>,
+    codec: &mut Codec,
+    src: &mut S,
+) -> Poll<Result<()>> {
+    if let Some(buf) = codec.source_mut() {
+        src.check().map_err(Error::Source)?;
+        match src.total_len() {
+            Some(len) => buf.set_len(len),
+            None => {
+                let window_end = buf.block_count() - 1;
+                ready!(poll_fill_source(cx, buf, src, window_end, None))?;
+            }
+        }
+    }
+    Poll::Ready(codec.attach_source())
+}
+
+/// Answers xdelta3's request for source block `blkno`.
+pub(crate) fn poll_getblk<S: BlockSource>(
+    cx: &mut Context<'_>,
+    codec: &mut Codec,
+    src: &mut S,
+    blkno: usize,
+) -> Poll<Result<()>> {
+    let position = codec.position();
+    if let Some(buf) = codec.source_mut() {
+        match src.mapped() {
+            Some(data) => buf.getblk_mapped(data),
+            None => {
+                ready!(poll_fill_source(cx, buf, src, blkno, Some(position)))?;
+                buf.getblk();
+            }
+        }
+    }
+    Poll::Ready(Ok(()))
+}
+
+/// Runs `f` once for the blocking front end, which has nothing to wait on.
+pub(crate) fn now<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce(&mut Context<'_>) -> Poll<Result<T>>,
+{
+    // Safety: the vtable functions do nothing, and never look at the data pointer.
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    match f(&mut cx) {
+        Poll::Ready(result) => result,
+        Poll::Pending => Err(Error::Source(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "source returned Poll::Pending to a blocking function",
+        ))),
+    }
+}
+
+/// A waker that does nothing, since the blocking front end has nothing to wake.
+fn noop_raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    RawWaker::new(std::ptr::null(), &VTABLE)
+}
@@ -0,0 +1,363 @@
+//! Source buffering shared by the streaming front ends.
+//!
+//! The buffer does no I/O itself: while [`SrcBuffer::needs_block`] is true, the async and
+//! blocking front ends read the block named by [`SrcBuffer::next_read`] into the slot
+//! returned by [`SrcBuffer::next_block`], and report it with [`SrcBuffer::push_block`].
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::binding;
+use super::{Error, Position};
+use log::debug;
+
+/// A cached source block.
+struct Slot {
+    /// Number of the block held, or `None` while the slot is being filled.
+    blkno: Option<usize>,
+    len: usize,
+    /// Tick of the last use, the key of the slot in `SrcBuffer::lru`.
+    used: u64,
+}
+
+/// Cache of the most recently used source blocks.
+///
+/// At most `block_count` blocks are kept; when a new block is read, the least recently
+/// used one is evicted. The cache only grows as blocks are read, so a source smaller than
+/// the window only takes as much memory as its own length.
+///
+/// Blocks are looked up through `index` and the eviction order is kept in `lru`, so
+/// neither needs a scan of every slot.
+///
+/// A forward-only source can only be read at `next_blkno`, so an evicted block cannot be
+/// read again. A seekable source can be read at any block.
+pub(crate) struct SrcBuffer {
+    pub(crate) src: binding::xd3_source,
+    blksize: usize,
+    block_count: usize,
+    slots: Vec<Slot>,
+    /// Slot holding each block.
+    index: HashMap<usize, usize>,
+    /// Slots holding a block, by the tick of their last use.
+    lru: BTreeMap<u64, usize>,
+    /// Slots that hold no block.
+    free: Vec<usize>,
+    /// Slot `i` is `buf[i * blksize..(i + 1) * blksize]`.
+    buf: Vec<u8>,
+    /// Number of the block following the last one read.
+    next_blkno: usize,
+    /// Total length of the source, once known.
+    len: Option<u64>,
+    /// Slot handed out by the last `next_block`.
+    filling: usize,
+    /// Block being read into the `filling` slot, until `push_block` is called.
+    reading: Option<usize>,
+    tick: u64,
+    /// Bytes read from the source, counting blocks read more than once each time.
+    bytes_read: u64,
+}
+
+impl SrcBuffer {
+    pub(crate) fn new(winsize: usize, blksize: usize) -> Self {
+        let mut src: binding::xd3_source = unsafe { std::mem::zeroed() };
+        src.blksize = blksize as u32;
+        src.max_winsize = winsize as u64;
+
+        Self {
+            src,
+            blksize,
+            block_count: winsize / blksize,
+            slots: Vec::new(),
+            index: HashMap::new(),
+            lru: BTreeMap::new(),
+            free: Vec::new(),
+            buf: Vec::new(),
+            next_blkno: 0,
+            len: None,
+            filling: 0,
+            reading: None,
+            tick: 0,
+            bytes_read: 0,
+        }
+    }
+
+    /// Number of blocks read before the source is attached to the stream.
+    ///
+    /// Reading a whole window up front lets the source length be passed to xdelta3
+    /// when the source fits in it.
+    pub(crate) fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    /// Total length of the source, once the end of it has been read.
+    pub(crate) fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    /// Sets the length of a source whose size is known before it is read.
+    pub(crate) fn set_len(&mut self, len: u64) {
+        self.len = Some(len);
+    }
+
+    /// Shrinks the window to the source, once its length is known, so that the encoder
+    /// does not size its tables for a window larger than the source.
+    pub(crate) fn fit_window(&mut self) {
+        if let Some(len) = self.len {
+            let blocks = len.div_ceil(self.blksize as u64).max(1);
+            if blocks < self.block_count as u64 {
+                self.block_count = blocks as usize;
+                self.src.max_winsize = blocks * self.blksize as u64;
+            }
+        }
+    }
+
+    /// Bytes read from the source so far.
+    pub(crate) fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Number of the block xdelta3 asked for with `XD3_GETSRCBLK`.
+    pub(crate) fn requested(&self) -> usize {
+        self.src.getblkno as usize
+    }
+
+    fn is_past_end(&self, idx: usize) -> bool {
+        match self.len {
+            Some(len) => self.offset(idx) >= len,
+            None => false,
+        }
+    }
+
+    fn find(&self, idx: usize) -> Option<usize> {
+        self.index.get(&idx).copied()
+    }
+
+    /// Marks slot `idx` as the most recently used.
+    fn touch(&mut self, idx: usize) {
+        self.tick += 1;
+        let slot = &mut self.slots[idx];
+        self.lru.remove(&slot.used);
+        slot.used = self.tick;
+        self.lru.insert(self.tick, idx);
+    }
+
+    /// Returns true if block `idx` has to be read before it can be handed to xdelta3.
+    pub(crate) fn needs_block(&self, idx: usize) -> bool {
+        !self.is_past_end(idx) && self.find(idx).is_none()
+    }
+
+    /// Returns the number of the block to read next on the way to block `idx`.
+    ///
+    /// A forward-only source has to read every block up to `idx`; `None` is returned if
+    /// `idx` has already been read and evicted.
+    pub(crate) fn next_read(&self, idx: usize, seekable: bool) -> Option<usize> {
+        if seekable {
+            Some(idx)
+        } else if idx >= self.next_blkno {
+            Some(self.next_blkno)
+        } else {
+            None
+        }
+    }
+
+    /// Error for a block that a forward-only source can no longer provide.
+    pub(crate) fn evicted(&self, idx: usize, position: Option<Position>) -> Error {
+        Error::TooFarBack {
+            msg: format!(
+                "source block {} is no longer buffered; use a seekable source or a larger source window",
+                idx
+            ),
+            position,
+        }
+    }
+
+    /// Byte offset of block `blkno` in the source.
+    pub(crate) fn offset(&self, blkno: usize) -> u64 {
+        blkno as u64 * self.blksize as u64
+    }
+
+    /// Returns a slot to read block `blkno` into, evicting the least recently used block
+    /// if the cache is full.
+    ///
+    /// The caller reads into it and then reports the length with [`push_block`](Self::push_block).
+    /// Until then, asking for the same block again returns the same slot, with the data
+    /// read into it so far.
+    pub(crate) fn next_block(&mut self, blkno: usize) -> &mut [u8] {
+        if self.reading == Some(blkno) {
+            let start = self.filling * self.blksize;
+            return &mut self.buf[start..start + self.blksize];
+        }
+        let idx = if self.reading.is_some() {
+            // The read of another block was given up; its slot holds nothing.
+            self.filling
+        } else if let Some(idx) = self.free.pop() {
+            idx
+        } else if self.slots.len() < self.block_count {
+            self.slots.push(Slot {
+                blkno: None,
+                len: 0,
+                used: 0,
+            });
+            self.buf.resize(self.slots.len() * self.blksize, 0u8);
+            self.slots.len() - 1
+        } else {
+            let (_, idx) = self
+                .lru
+                .pop_first()
+                .expect("source window holds at least one block");
+            idx
+        };
+        debug!("next_block blkno={}, slot={}", blkno, idx);
+
+        self.reading = Some(blkno);
+        let slot = &mut self.slots[idx];
+        if let Some(evicted) = slot.blkno.take() {
+            self.index.remove(&evicted);
+        }
+        slot.len = 0;
+        slot.used = 0;
+        self.filling = idx;
+        let start = idx * self.blksize;
+        &mut self.buf[start..start + self.blksize]
+    }
+
+    /// Records that `read_len` bytes of block `blkno` were read into the slot from
+    /// [`next_block`](Self::next_block).
+    ///
+    /// A short read marks the end of the source.
+    pub(crate) fn push_block(&mut self, blkno: usize, read_len: usize) {
+        debug!("blkno={}, read_len={}", blkno, read_len);
+
+        self.reading = None;
+        self.next_blkno = blkno + 1;
+        self.bytes_read += read_len as u64;
+        if read_len != self.blksize {
+            let len = self.offset(blkno) + read_len as u64;
+            self.len = Some(self.len.map_or(len, |known| known.min(len)));
+        }
+        if read_len == 0 {
+            self.free.push(self.filling);
+            return;
+        }
+
+        let slot = &mut self.slots[self.filling];
+        slot.blkno = Some(blkno);
+        slot.len = read_len;
+        self.index.insert(blkno, self.filling);
+        self.touch(self.filling);
+    }
+
+    /// Answers `XD3_GETSRCBLK` with the requested block, which must have been read.
+    pub(crate) fn getblk(&mut self) {
+        debug!(
+            "getsrcblk: curblkno={}, getblkno={}",
+            self.src.curblkno, self.src.getblkno,
+        );
+
+        let blkno = self.requested();
+        let data = match self.find(blkno) {
+            Some(idx) => {
+                self.touch(idx);
+                let start = idx * self.blksize;
+                &self.buf[start..start + self.slots[idx].len]
+            }
+            // Past the end of the source.
+            None => &[],
+        };
+        set_block(&mut self.src, data, self.len);
+    }
+
+    /// Answers `XD3_GETSRCBLK` with the requested block of `data`, the whole source.
+    ///
+    /// The block is not copied, so `data` must outlive the stream.
+    pub(crate) fn getblk_mapped(&mut self, data: &[u8]) {
+        debug!(
+            "getsrcblk mapped: curblkno={}, getblkno={}",
+            self.src.curblkno, self.src.getblkno,
+        );
+
+        let start = self.offset(self.requested()).min(data.len() as u64) as usize;
+        let end = data.len().min(start + self.blksize);
+        self.bytes_read += (end - start) as u64;
+        set_block(&mut self.src, &data[start..end], Some(data.len() as u64));
+    }
+}
+
+// SAFETY: `src.curblk` points into `buf`, which moves with the buffer, or into the mapped
+// source, which is shared and only read. The other pointers in `src` belong to the stream
+// the source is attached to, which is owned next to the buffer and moves with it.
+unsafe impl Send for SrcBuffer {}
+
+/// Points `src` at `data`, the block xdelta3 asked for, in a source of length `len`.
+fn set_block(src: &mut binding::xd3_source, data: &[u8], len: Option<u64>) {
+    src.curblkno = src.getblkno;
+    src.curblk = data.as_ptr();
+    src.onblk = data.len() as u32;
+
+    match len {
+        Some(len) => {
+            let blksize = u64::from(src.blksize);
+            let max_blkno = len.saturating_sub(1) / blksize;
+            src.eof_known = 1;
+            src.max_blkno = max_blkno;
+            src.onlastblk = (len - max_blkno * blksize) as u32;
+        }
+        None => {
+            src.eof_known = 0;
+            src.max_blkno = src.curblkno;
+            src.onlastblk = src.onblk;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_fits_source() {
+        let mut buf = SrcBuffer::new(1 << 26, 1 << 20);
+        buf.set_len(100_000);
+        buf.fit_window();
+        assert_eq!(buf.src.max_winsize, 1 << 20);
+        assert_eq!(buf.block_count(), 1);
+
+        let mut buf = SrcBuffer::new(1 << 26, 1 << 16);
+        buf.set_len((5 << 16) + 1);
+        buf.fit_window();
+        assert_eq!(buf.src.max_winsize, 6 << 16);
+        assert_eq!(buf.block_count(), 6);
+
+        let mut buf = SrcBuffer::new(1 << 20, 1 << 16);
+        buf.set_len(1 << 30);
+        buf.fit_window();
+        assert_eq!(buf.src.max_winsize, 1 << 20);
+        assert_eq!(buf.block_count(), 16);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        const BLKSIZE: usize = 1 << 14;
+        let mut buf = SrcBuffer::new(3 * BLKSIZE, BLKSIZE);
+        let read = |buf: &mut SrcBuffer, blkno: usize| {
+            buf.next_block(blkno)[0] = blkno as u8;
+            buf.push_block(blkno, BLKSIZE);
+        };
+        for blkno in 0..3 {
+            read(&mut buf, blkno);
+        }
+        buf.src.getblkno = 0;
+        buf.getblk();
+
+        read(&mut buf, 3);
+        assert!(!buf.needs_block(0));
+        assert!(buf.needs_block(1));
+        assert!(!buf.needs_block(2));
+        assert!(!buf.needs_block(3));
+
+        read(&mut buf, 1);
+        assert!(buf.needs_block(2));
+        buf.src.getblkno = 1;
+        buf.getblk();
+        assert_eq!(unsafe { *buf.src.curblk }, 1);
+    }
+}
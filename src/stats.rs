@@ -0,0 +1,66 @@
+//! Statistics of a finished encode or decode.
+
+use std::time::Duration;
+
+use super::binding;
+
+/// Statistics of a finished encode or decode, returned by the streaming functions in
+/// [`stream`](crate::stream) and [`sync`](crate::sync).
+///
+/// Only the encoder counts the instructions it emits, so
+/// [`instructions`](Self::instructions) is `None` after a decode.
+///
+/// ```
+/// let input = [7u8; 4000];
+/// let mut patch = Vec::new();
+/// let stats = xdelta3::sync::encode(&input[..], &[][..], &mut patch).unwrap();
+/// assert_eq!(stats.input_bytes, 4000);
+/// assert_eq!(stats.output_bytes, patch.len() as u64);
+/// let instructions = stats.instructions.unwrap();
+/// assert_eq!(instructions.add_bytes + instructions.run_bytes + instructions.copy_bytes, 4000);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Input consumed: the updated data when encoding, the patch when decoding.
+    pub input_bytes: u64,
+    /// Source data read. A block read again after it was evicted counts again.
+    pub source_bytes: u64,
+    /// Output produced.
+    pub output_bytes: u64,
+    /// Number of windows.
+    pub windows: u64,
+    /// How the target was put together. Only the encoder counts its instructions, so this
+    /// is `None` after a decode.
+    pub instructions: Option<InstructionStats>,
+    /// Time spent in xdelta3.
+    pub codec_time: Duration,
+    /// Time spent reading the input and the source, and writing the output.
+    pub io_time: Duration,
+}
+
+/// Bytes of the target produced by each kind of VCDIFF instruction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InstructionStats {
+    /// Bytes stored in the patch (ADD).
+    pub add_bytes: u64,
+    /// Bytes copied from the source or from earlier in the target (COPY).
+    pub copy_bytes: u64,
+    /// Bytes repeating a single byte stored in the patch (RUN).
+    pub run_bytes: u64,
+    /// Bytes of `copy_bytes` copied from the source, summed over the COPY instructions.
+    /// Copies that overlap or repeat count each time, so this is not the number of
+    /// distinct source bytes referenced, and can be larger than the source.
+    pub copy_bytes_from_source: u64,
+}
+
+impl InstructionStats {
+    /// Totals counted by the encoder of `stream` so far.
+    pub(crate) fn new(stream: &binding::xd3_stream) -> Self {
+        Self {
+            add_bytes: stream.l_add,
+            copy_bytes: stream.l_scpy + stream.l_tcpy,
+            run_bytes: stream.l_run,
+            copy_bytes_from_source: stream.l_scpy,
+        }
+    }
+}
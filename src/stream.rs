@@ -1,270 +1,1097 @@
 use futures_io::*;
+use futures_util::future::poll_fn;
 use futures_util::io::*;
-use std::ops::Range;
+use futures_util::ready;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use super::binding;
+use super::codec::{Codec, Step};
+use super::config::Settings;
+use super::source::{
+    out_of_order, poll_fill_block, poll_getblk, poll_start_source, BlockSource, Blocks,
+};
+use super::xd3::{Mode, Xd3Stream};
+#[cfg(feature = "mmap")]
+use super::MmapSource;
+use super::{DecoderConfig, EncoderConfig, Error, Result, Source, Stats};
 use log::debug;
 
-#[allow(unused)]
-const XD3_DEFAULT_WINSIZE: usize = 1 << 23;
-const XD3_DEFAULT_SRCWINSZ: usize = 1 << 26;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
-struct SrcBuffer<R> {
-    src: binding::xd3_source,
+/// A source read front to back from an async reader
+///
+/// Short reads are fine: each block is read until it is full or the reader ends.
+pub struct ReadSource<R> {
     read: R,
-    read_len: usize,
-    eof_known: bool,
+    /// Number of the block to be read next.
+    next: u64,
+    /// Bytes of that block read so far.
+    filled: usize,
+}
 
-    block_count: usize,
-    block_offset: usize,
-    buf: Box<[u8]>,
+impl<R: AsyncRead + Unpin> ReadSource<R> {
+    /// Reads the source from `read`.
+    pub fn new(read: R) -> Self {
+        Self {
+            read,
+            next: 0,
+            filled: 0,
+        }
+    }
 }
 
-impl<R: AsyncRead + Unpin> SrcBuffer<R> {
-    async fn new(mut read: R) -> Option<Self> {
-        let block_count = 64;
-        let max_winsize = XD3_DEFAULT_SRCWINSZ;
-        let blksize = max_winsize / block_count;
+impl<R: AsyncRead + Unpin> Source for ReadSource<R> {
+    fn total_len(&self) -> Option<u64> {
+        None
+    }
 
-        let mut src: binding::xd3_source = unsafe { std::mem::zeroed() };
-        src.blksize = blksize as u32;
-        src.max_winsize = max_winsize as u64;
+    fn can_seek(&self) -> bool {
+        false
+    }
 
-        let mut buf = Vec::with_capacity(max_winsize);
-        buf.resize(max_winsize, 0u8);
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if blkno != self.next {
+            return Poll::Ready(Err(out_of_order(blkno)));
+        }
+        let read = &mut self.read;
+        let len = ready!(poll_fill_block(&mut self.filled, buf, |_, buf| {
+            Pin::new(&mut *read).poll_read(cx, buf)
+        }))?;
+        self.next += 1;
+        Poll::Ready(Ok(len))
+    }
+}
 
-        let read_len = read.read(&mut buf).await.ok()?;
-        debug!("SrcBuffer::new read_len={}", read_len);
+/// A source read from a seekable async reader, at whatever block is needed
+///
+/// The source starts at the position the reader had when it was passed in, and ends at
+/// the end of the reader.
+pub struct SeekSource<R> {
+    read: R,
+    /// Offset of the start of the source in `read`.
+    base: u64,
+    /// Current offset of `read`.
+    pos: u64,
+    len: u64,
+    /// Block being read, and how much of it has been read.
+    block: u64,
+    filled: usize,
+}
 
-        Some(Self {
-            src,
+impl<R: AsyncRead + AsyncSeek + Unpin> SeekSource<R> {
+    /// Reads the source from `read`, starting at its current position.
+    pub async fn new(mut read: R) -> io::Result<Self> {
+        let base = read.seek(io::SeekFrom::Current(0)).await?;
+        let end = read.seek(io::SeekFrom::End(0)).await?;
+        Ok(Self {
             read,
-            read_len,
-            eof_known: read_len != buf.len(),
-
-            block_count,
-            block_offset: 0,
-            buf: buf.into_boxed_slice(),
+            base,
+            pos: end,
+            len: end.saturating_sub(base),
+            block: 0,
+            filled: 0,
         })
     }
+}
 
-    async fn fetch(&mut self) -> Option<bool> {
-        let idx = self.block_offset;
-        let r = self.block_range(idx);
-        let block = &mut self.buf[r.clone()];
-        let read_len = self.read.read(block).await.ok()?;
-        debug!(
-            "range={:?}, block_len={}, read_len={}",
-            r,
-            block.len(),
-            read_len
-        );
-
-        self.block_offset += 1;
-        self.read_len += read_len;
-
-        Some(read_len != block.len())
+impl<R: AsyncRead + AsyncSeek + Unpin> Source for SeekSource<R> {
+    fn total_len(&self) -> Option<u64> {
+        Some(self.len)
     }
 
-    async fn prepare(&mut self, idx: usize) -> Option<()> {
-        while !self.eof_known && idx >= self.block_offset + self.block_count {
-            debug!(
-                "prepare idx={}, block_offset={}, block_count={}",
-                idx, self.block_offset, self.block_count
-            );
-            let eof = self.fetch().await?;
-            if eof {
-                debug!("eof");
-                self.eof_known = true;
-                break;
-            }
+    fn poll_block(
+        &mut self,
+        cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if blkno != self.block {
+            self.block = blkno;
+            self.filled = 0;
         }
-        Some(())
+        let start = self.base + blkno * buf.len() as u64;
+        let (read, pos) = (&mut self.read, &mut self.pos);
+        poll_fill_block(&mut self.filled, buf, |offset, buf| {
+            let target = start + offset as u64;
+            if *pos != target {
+                *pos = ready!(Pin::new(&mut *read).poll_seek(cx, io::SeekFrom::Start(target)))?;
+            }
+            let read_len = ready!(Pin::new(&mut *read).poll_read(cx, buf))?;
+            *pos += read_len as u64;
+            Poll::Ready(Ok(read_len))
+        })
     }
+}
 
-    fn block_range(&self, idx: usize) -> Range<usize> {
-        debug!("idx={}, offset={}", idx, self.block_offset);
-        assert!(idx >= self.block_offset && idx < self.block_offset + self.block_count);
+/// Function to decode the difference data from a stream
+///
+/// This is the streaming counterpart of [`decode`](crate::decode). The patch is read from
+/// `input`, the original data from `src`, and the patched data is written to `out`.
+/// On success, the [`Stats`] of the decode are returned.
+///
+/// The returned future does not borrow the configuration, and is `Send` when `input`,
+/// `src` and `out` are, so it can be spawned on a multi-threaded runtime. Dropping it
+/// before it completes frees the xdelta3 stream.
+pub fn decode_async<R1, R2, W>(input: R1, src: R2, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_with(input, src, out, &DecoderConfig::default())
+}
 
-        let idx = idx % self.block_count;
-        let start = (self.src.blksize as usize) * idx;
-        let end = (self.src.blksize as usize) * (idx + 1);
+/// Function to decode the difference data from a stream with custom settings
+///
+/// This works like [`decode_async`], with the settings taken from `config`.
+pub fn decode_async_with<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_source_with(input, ReadSource::new(src), out, config)
+}
 
-        let start = start.min(self.read_len);
-        let end = end.min(self.read_len);
+/// Function to decode the difference data against a seekable source
+///
+/// This works like [`decode_async`], but blocks of `src` that no longer fit in the
+/// source window are read again when the patch copies from them, so any valid patch can
+/// be applied. The source starts at the current position of `src`.
+pub fn decode_async_seekable<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_seekable_with(input, src, out, &DecoderConfig::default())
+}
 
-        start..end
+/// Function to decode the difference data against a seekable source with custom settings
+///
+/// This works like [`decode_async_seekable`], with the settings taken from `config`.
+pub fn decode_async_seekable_with<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let codec = decoder(config, true);
+    async move {
+        let src = SeekSource::new(src).await.map_err(Error::Source)?;
+        process_async(codec, input, Some(Blocks::new(src)), out, None).await
     }
+}
 
-    async fn getblk(&mut self) {
-        debug!(
-            "getsrcblk: curblkno={}, getblkno={}",
-            self.src.curblkno, self.src.getblkno,
-        );
-
-        let blkno = self.src.getblkno as usize;
-        self.prepare(blkno).await;
-        let range = self.block_range(blkno);
-
-        let src = &mut self.src;
-        let data = &self.buf[range];
-
-        src.curblkno = src.getblkno;
-        src.curblk = data.as_ptr();
-        src.onblk = data.len() as u32;
-
-        src.eof_known = self.eof_known as i32;
-        if !self.eof_known {
-            src.max_blkno = src.curblkno;
-            src.onlastblk = src.onblk;
-        } else {
-            src.max_blkno = (self.block_offset + self.block_count - 1) as u64;
-            src.onlastblk = (self.read_len % src.blksize as usize) as u32;
-        }
-    }
+/// Function to decode the difference data against any [`Source`]
+///
+/// This works like [`decode_async`], with the original data read from `src` a block at a
+/// time.
+pub fn decode_async_source<R, S, W>(input: R, src: S, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_source_with(input, src, out, &DecoderConfig::default())
 }
 
-struct Xd3Stream {
-    inner: binding::xd3_stream,
+/// Function to decode the difference data against any [`Source`] with custom settings
+///
+/// This works like [`decode_async_source`], with the settings taken from `config`.
+pub fn decode_async_source_with<R, S, W>(
+    input: R,
+    src: S,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        decoder(config, true),
+        input,
+        Some(Blocks::new(src)),
+        out,
+        None,
+    )
 }
-impl Xd3Stream {
-    fn new() -> Self {
-        let inner: binding::xd3_stream = unsafe { std::mem::zeroed() };
-        return Self { inner };
-    }
+
+/// Function to decode the difference data against a memory-mapped source
+///
+/// This works like [`decode_async`], but source blocks are read straight from the map
+/// without being copied, and the patch may copy from anywhere in the source.
+///
+/// A file already truncated when this starts is reported as an error, but one truncated
+/// later makes the operating system kill the process with `SIGBUS`. Read files that can
+/// change with [`decode_async_source`] and a [`File`](std::fs::File) instead.
+#[cfg(feature = "mmap")]
+pub async fn decode_async_mmap<R, W>(input: R, src: &MmapSource, out: W) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_mmap_with(input, src, out, &DecoderConfig::default()).await
 }
-impl Drop for Xd3Stream {
-    fn drop(&mut self) {
-        unsafe {
-            binding::xd3_free_stream(&mut self.inner as *mut _);
-        }
-    }
+
+/// Function to decode the difference data against a memory-mapped source with custom settings
+///
+/// This works like [`decode_async_mmap`], with the settings taken from `config`.
+///
+/// Like [`decode_async_mmap`], this kills the process with `SIGBUS` if the file is truncated while
+/// xdelta3 reads from the map.
+#[cfg(feature = "mmap")]
+pub async fn decode_async_mmap_with<R, W>(
+    input: R,
+    src: &MmapSource,
+    out: W,
+    config: &DecoderConfig,
+) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    process_async(decoder(config, true), input, Some(src), out, None).await
 }
 
-pub async fn decode_async<R1, R2, W>(input: R1, src: R2, out: W) -> Option<()>
+/// Function to generate the difference data from a stream
+///
+/// This is the streaming counterpart of [`encode`](crate::encode). The updated data is read
+/// from `input`, the original data from `src`, and the patch is written to `out`.
+/// On success, the [`Stats`] of the encode are returned.
+///
+/// The returned future does not borrow the configuration, and is `Send` when `input`,
+/// `src` and `out` are, so it can be spawned on a multi-threaded runtime. Dropping it
+/// before it completes frees the xdelta3 stream.
+///
+/// The encoding itself runs on the task polling the future. The functions in
+/// `stream::tokio` run it on tokio's blocking thread pool instead.
+pub fn encode_async<R1, R2, W>(input: R1, src: R2, out: W) -> impl Future<Output = Result<Stats>>
 where
     R1: AsyncRead + Unpin,
     R2: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    process_async(Mode::Decode, input, src, out).await
+    encode_async_with(input, src, out, &EncoderConfig::default())
 }
 
-pub async fn encode_async<R1, R2, W>(input: R1, src: R2, out: W) -> Option<()>
+/// Function to generate the difference data from a stream with custom settings
+///
+/// This works like [`encode_async`], with the compression level, matcher and buffer
+/// sizes taken from `config`.
+pub fn encode_async_with<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &EncoderConfig,
+) -> impl Future<Output = Result<Stats>>
 where
     R1: AsyncRead + Unpin,
     R2: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    process_async(Mode::Encode, input, src, out).await
+    encode_async_source_with(input, ReadSource::new(src), out, config)
+}
+
+/// Function to generate the difference data against any [`Source`]
+///
+/// This works like [`encode_async`], with the original data read from `src` a block at a
+/// time.
+pub fn encode_async_source<R, S, W>(input: R, src: S, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    encode_async_source_with(input, src, out, &EncoderConfig::default())
 }
 
-enum Mode {
-    Encode,
-    Decode,
+/// Function to generate the difference data against any [`Source`] with custom settings
+///
+/// This works like [`encode_async_source`], with the settings taken from `config`.
+pub fn encode_async_source_with<R, S, W>(
+    input: R,
+    src: S,
+    out: W,
+    config: &EncoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        encoder(config, true),
+        input,
+        Some(Blocks::new(src)),
+        out,
+        None,
+    )
 }
 
-async fn process_async<R1, R2, W>(mode: Mode, mut input: R1, src: R2, mut out: W) -> Option<()>
+/// Function to generate the difference data against a memory-mapped source
+///
+/// This works like [`encode_async`], but source blocks are read straight from the map
+/// without being copied.
+///
+/// A file already truncated when this starts is reported as an error, but one truncated
+/// later makes the operating system kill the process with `SIGBUS`. Read files that can
+/// change with [`encode_async_source`] and a [`File`](std::fs::File) instead.
+#[cfg(feature = "mmap")]
+pub async fn encode_async_mmap<R, W>(input: R, src: &MmapSource, out: W) -> Result<Stats>
 where
-    R1: AsyncRead + Unpin,
-    R2: AsyncRead + Unpin,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    encode_async_mmap_with(input, src, out, &EncoderConfig::default()).await
+}
+
+/// Function to generate the difference data against a memory-mapped source with custom
+/// settings
+///
+/// This works like [`encode_async_mmap`], with the settings taken from `config`.
+///
+/// Like [`encode_async_mmap`], this kills the process with `SIGBUS` if the file is truncated while
+/// xdelta3 reads from the map.
+#[cfg(feature = "mmap")]
+pub async fn encode_async_mmap_with<R, W>(
+    input: R,
+    src: &MmapSource,
+    out: W,
+    config: &EncoderConfig,
+) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    process_async(encoder(config, true), input, Some(src), out, None).await
+}
+
+/// Function to compress a stream without a source
+///
+/// This is the streaming counterpart of [`compress`](crate::compress). The data is read
+/// from `input` and the patch is written to `out`.
+pub fn compress_async<R, W>(input: R, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    compress_async_with(input, out, &EncoderConfig::default())
+}
+
+/// Function to compress a stream without a source, with custom settings
+///
+/// This works like [`compress_async`], with the settings taken from `config`.
+pub fn compress_async_with<R, W>(
+    input: R,
+    out: W,
+    config: &EncoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        encoder(config, false),
+        input,
+        None::<Blocks<&[u8]>>,
+        out,
+        None,
+    )
+}
+
+/// Function to decompress a stream made by [`compress_async`]
+///
+/// This is the streaming counterpart of [`decompress`](crate::decompress).
+pub fn decompress_async<R, W>(input: R, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decompress_async_with(input, out, &DecoderConfig::default())
+}
+
+/// Function to decompress a stream made by [`compress_async`], with custom settings
+///
+/// This works like [`decompress_async`], with the settings taken from `config`.
+pub fn decompress_async_with<R, W>(
+    input: R,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
+{
+    process_async(
+        decoder(config, false),
+        input,
+        None::<Blocks<&[u8]>>,
+        out,
+        None,
+    )
+}
+
+/// Function to read the application header of a patch from a stream
+///
+/// This is the streaming counterpart of [`app_header`](crate::app_header). `input` is read
+/// up to the end of the patch header, and possibly a little further.
+pub async fn app_header_async<R>(mut input: R) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
 {
     let mut stream = Xd3Stream::new();
-    let stream = &mut stream.inner;
     let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
-    cfg.winsize = XD3_DEFAULT_WINSIZE as u32;
-
-    let mut src_buf = SrcBuffer::new(src).await?;
+    stream.config(&mut cfg)?;
 
-    let ret = unsafe { binding::xd3_config_stream(stream, &mut cfg) };
-    if ret != 0 {
-        return None;
+    let mut input_buf = vec![0u8; 4096];
+    loop {
+        use binding::xd3_rvalues::*;
+        match stream.step(Mode::Decode)? {
+            XD3_GOTHEADER => return Ok(stream.app_header().map(<[u8]>::to_vec)),
+            XD3_INPUT => {
+                let read_size = input.read(&mut input_buf).await.map_err(Error::Input)?;
+                if read_size == 0 {
+                    return Err(Error::InvalidInput {
+                        msg: "patch ends inside its header".to_owned(),
+                        position: Some(stream.position()),
+                    });
+                }
+                stream.avail_input(&input_buf[..read_size]);
+            }
+            _ => {
+                // nothing else happens before the header is complete
+            }
+        }
     }
+}
+
+/// Creates an encoding codec from `config`, before the future that runs it.
+fn encoder(config: &EncoderConfig, source: bool) -> Result<Codec> {
+    Codec::new(Mode::Encode, config.settings()?, source)
+}
+
+/// Creates a decoding codec from `config`, before the future that runs it.
+fn decoder(config: &DecoderConfig, source: bool) -> Result<Codec> {
+    Codec::new(Mode::Decode, config.settings()?, source)
+}
+
+/// Runs a codec step away from the task, such as on a blocking thread pool, and hands the
+/// codec back with the step.
+type Offload = fn(Codec) -> Stepped;
+
+/// A codec step running away from the task.
+type Stepped = Pin<Box<dyn Future<Output = Result<(Codec, Step)>> + Send>>;
+
+/// Runs `codec` to the end, with its steps run by `offload` if it is set.
+///
+/// The codec is created before the future, so the future does not borrow the
+/// configuration.
+async fn process_async<R, S, W>(
+    codec: Result<Codec>,
+    mut input: R,
+    mut src: Option<S>,
+    mut out: W,
+    offload: Option<Offload>,
+) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    S: BlockSource,
+    W: AsyncWrite + Unpin,
+{
+    let mut io_time = Duration::default();
+    let result = run(
+        codec?,
+        &mut input,
+        &mut src,
+        &mut out,
+        offload,
+        &mut io_time,
+    )
+    .await;
 
-    let ret = unsafe { binding::xd3_set_source(stream, &mut src_buf.src) };
-    if ret != 0 {
-        return None;
+    // Flush even on error, so that a cancelled run leaves its complete windows in `out`.
+    let started = Instant::now();
+    let flushed = out.flush().await.map_err(Error::Output);
+    io_time += started.elapsed();
+    match result {
+        Ok(codec) => flushed.map(|()| codec.stats(io_time)),
+        Err(e @ Error::Cancelled { .. }) => flushed.and(Err(e)),
+        Err(e) => Err(e),
     }
+}
 
-    let input_buf_size = stream.winsize as usize;
-    debug!("stream.winsize={}", input_buf_size);
-    let mut input_buf = Vec::with_capacity(input_buf_size);
-    input_buf.resize(input_buf_size, 0u8);
-    let mut eof = false;
+/// Steps `codec` to the end of its input, reading and writing as it asks.
+async fn run<R, S, W>(
+    mut codec: Codec,
+    input: &mut R,
+    src: &mut Option<S>,
+    out: &mut W,
+    offload: Option<Offload>,
+    io_time: &mut Duration,
+) -> Result<Codec>
+where
+    R: AsyncRead + Unpin,
+    S: BlockSource,
+    W: AsyncWrite + Unpin,
+{
+    if let Some(read) = src {
+        let started = Instant::now();
+        poll_fn(|cx| poll_start_source(cx, &mut codec, read)).await?;
+        *io_time += started.elapsed();
+    }
 
-    'outer: while !eof {
-        let read_size = match input.read(&mut input_buf).await {
-            Ok(n) => n,
-            Err(_e) => {
-                debug!("error on read: {:?}", _e);
-                return None;
+    loop {
+        let step = match offload {
+            Some(offload) => {
+                let (stepped, step) = offload(codec).await?;
+                codec = stepped;
+                step
             }
+            None => codec.step()?,
         };
-        debug!("read_size={}", read_size);
-        if read_size == 0 {
-            // xd3_set_flags
-            stream.flags = binding::xd3_flags::XD3_FLUSH as i32;
-            eof = true;
+        match step {
+            Step::NeedInput => {
+                let started = Instant::now();
+                let read_size = input.read(codec.input_buf()).await.map_err(|e| {
+                    debug!("error on read: {:?}", e);
+                    Error::Input(e)
+                })?;
+                *io_time += started.elapsed();
+                codec.provide_input(read_size);
+            }
+            Step::Output => {
+                let started = Instant::now();
+                out.write_all(codec.output()).await.map_err(|e| {
+                    debug!("error on write: {:?}", e);
+                    Error::Output(e)
+                })?;
+                *io_time += started.elapsed();
+                let len = codec.output().len();
+                codec.consume_output(len);
+            }
+            Step::NeedSource(blkno) => {
+                if let Some(read) = src {
+                    let started = Instant::now();
+                    poll_fn(|cx| poll_getblk(cx, &mut codec, read, blkno)).await?;
+                    *io_time += started.elapsed();
+                }
+            }
+            Step::GotHeader | Step::WindowStart | Step::WindowFinish => {}
+            Step::Done => return Ok(codec),
         }
+    }
+}
 
-        // xd3_avail_input
-        stream.next_in = input_buf.as_ptr();
-        stream.avail_in = read_size as u32;
+/// Shared state of [`EncoderReader`] and [`DecoderReader`].
+struct CodecReader<R, S> {
+    codec: Codec,
+    input: R,
+    src: Blocks<ReadSource<S>>,
+    started: bool,
+    /// The step being served, kept while the reader waits for I/O.
+    step: Option<Step>,
+}
 
-        'inner: loop {
-            let ret: binding::xd3_rvalues = unsafe {
-                std::mem::transmute(match mode {
-                    Mode::Encode => binding::xd3_encode_input(stream),
-                    Mode::Decode => binding::xd3_decode_input(stream),
-                })
-            };
+impl<R, S> CodecReader<R, S>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncRead + Unpin,
+{
+    fn new(mode: Mode, settings: Settings<'_>, input: R, src: S) -> Result<Self> {
+        Ok(Self {
+            codec: Codec::new(mode, settings, true)?,
+            input,
+            src: Blocks::new(ReadSource::new(src)),
+            started: false,
+            step: None,
+        })
+    }
 
-            if stream.msg != std::ptr::null() {
-                debug!("ret={:?}, msg={:?}", ret, unsafe {
-                    std::ffi::CStr::from_ptr(stream.msg)
-                },);
-            } else {
-                debug!("ret={:?}", ret,);
-            }
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if !self.started {
+            ready!(poll_start_source(cx, &mut self.codec, &mut self.src))?;
+            self.started = true;
+        }
 
-            use binding::xd3_rvalues::*;
-            match ret {
-                XD3_INPUT => {
-                    continue 'outer;
-                    //
-                }
-                XD3_OUTPUT => {
-                    let mut out_data = unsafe {
-                        std::slice::from_raw_parts(stream.next_out, stream.avail_out as usize)
-                    };
-                    while !out_data.is_empty() {
-                        let n = match out.write(out_data).await {
-                            Ok(n) => n,
-                            Err(_e) => {
-                                debug!("error on write: {:?}", _e);
-                                return None;
-                            }
-                        };
-                        out_data = &out_data[n..];
-                    }
+        loop {
+            let step = match self.step {
+                Some(step) => step,
+                None => self.codec.step()?,
+            };
+            self.step = Some(step);
 
-                    // xd3_consume_output
-                    stream.avail_out = 0;
+            match step {
+                Step::NeedInput => {
+                    let read_size =
+                        ready!(Pin::new(&mut self.input).poll_read(cx, self.codec.input_buf()))
+                            .map_err(Error::Input)?;
+                    self.codec.provide_input(read_size);
                 }
-                XD3_GETSRCBLK => {
-                    src_buf.getblk().await;
+                Step::Output => {
+                    let out = self.codec.output();
+                    let len = out.len().min(buf.len());
+                    buf[..len].copy_from_slice(&out[..len]);
+                    self.codec.consume_output(len);
+                    self.step = None;
+                    return Poll::Ready(Ok(len));
                 }
-                XD3_GOTHEADER | XD3_WINSTART | XD3_WINFINISH => {
-                    // do nothing
+                Step::NeedSource(blkno) => {
+                    ready!(poll_getblk(cx, &mut self.codec, &mut self.src, blkno))?;
+                }
+                Step::GotHeader | Step::WindowStart | Step::WindowFinish => {}
+                Step::Done => return Poll::Ready(Ok(0)),
+            }
+            self.step = None;
+        }
+    }
+}
+
+/// Reader of the patch generated from a stream
+///
+/// This is a pull-based [`encode_async`]: the patch is produced as it is read, and the
+/// updated data and the original data are read from `input` and `src` as needed.
+///
+/// ```
+/// use futures::io::AsyncReadExt;
+/// use xdelta3::stream::EncoderReader;
+///
+/// futures::executor::block_on(async {
+///     let input: &[u8] = &[1, 2, 3, 4, 5, 6, 7];
+///     let src: &[u8] = &[1, 2, 4, 4, 7, 6, 7];
+///     let mut patch = Vec::new();
+///     let mut reader = EncoderReader::new(input, src).unwrap();
+///     reader.read_to_end(&mut patch).await.unwrap();
+///     assert_eq!(xdelta3::decode(&patch, src).unwrap(), input);
+/// });
+/// ```
+pub struct EncoderReader<R, S> {
+    inner: CodecReader<R, S>,
+}
+
+impl<R, S> EncoderReader<R, S>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncRead + Unpin,
+{
+    /// Creates a reader of the patch that turns the data in `src` into the data in `input`.
+    pub fn new(input: R, src: S) -> Result<Self> {
+        Self::with_config(input, src, &EncoderConfig::default())
+    }
+
+    /// Creates a reader like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(input: R, src: S, config: &EncoderConfig) -> Result<Self> {
+        let inner = CodecReader::new(Mode::Encode, config.settings()?, input, src)?;
+        Ok(Self { inner })
+    }
+}
+
+impl<R, S> AsyncRead for EncoderReader<R, S>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .inner
+            .poll_read(cx, buf)
+            .map_err(io::Error::from)
+    }
+}
+
+/// Reader of the data decoded from a stream
+///
+/// This is a pull-based [`decode_async`]: the patched data is produced as it is read, and
+/// the patch and the original data are read from `input` and `src` as needed.
+pub struct DecoderReader<R, S> {
+    inner: CodecReader<R, S>,
+}
+
+impl<R, S> DecoderReader<R, S>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncRead + Unpin,
+{
+    /// Creates a reader of the data made by applying the patch in `input` to `src`.
+    pub fn new(input: R, src: S) -> Result<Self> {
+        Self::with_config(input, src, &DecoderConfig::default())
+    }
+
+    /// Creates a reader like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(input: R, src: S, config: &DecoderConfig) -> Result<Self> {
+        let inner = CodecReader::new(Mode::Decode, config.settings()?, input, src)?;
+        Ok(Self { inner })
+    }
+}
+
+impl<R, S> AsyncRead for DecoderReader<R, S>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .inner
+            .poll_read(cx, buf)
+            .map_err(io::Error::from)
+    }
+}
+
+/// Shared state of [`EncoderWriter`] and [`DecoderWriter`].
+struct CodecWriter<S, W> {
+    codec: Codec,
+    src: Blocks<ReadSource<S>>,
+    out: W,
+    started: bool,
+    /// Number of bytes written into the input buffer and not yet handed to xdelta3.
+    filled: usize,
+    /// The step being served; `NeedInput` while xdelta3 waits for the input buffer.
+    step: Option<Step>,
+}
+
+impl<S, W> CodecWriter<S, W>
+where
+    S: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn new(mode: Mode, settings: Settings<'_>, src: S, out: W) -> Result<Self> {
+        Ok(Self {
+            codec: Codec::new(mode, settings, true)?,
+            src: Blocks::new(ReadSource::new(src)),
+            out,
+            started: false,
+            filled: 0,
+            step: None,
+        })
+    }
+
+    /// Runs xdelta3 until it waits for input or is done.
+    fn poll_drive(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if !self.started {
+            ready!(poll_start_source(cx, &mut self.codec, &mut self.src))?;
+            self.started = true;
+        }
+
+        loop {
+            let step = match self.step {
+                Some(step) => step,
+                None => self.codec.step()?,
+            };
+            self.step = Some(step);
+
+            match step {
+                Step::GotHeader | Step::WindowStart | Step::WindowFinish => {}
+                Step::NeedInput | Step::Done => return Poll::Ready(Ok(())),
+                Step::Output => {
+                    let len = ready!(Pin::new(&mut self.out).poll_write(cx, self.codec.output()))
+                        .map_err(Error::Output)?;
+                    if len == 0 {
+                        return Poll::Ready(Err(Error::Output(io::ErrorKind::WriteZero.into())));
+                    }
+                    self.codec.consume_output(len);
                 }
-                XD3_TOOFARBACK | XD3_INTERNAL | XD3_INVALID | XD3_INVALID_INPUT | XD3_NOSECOND
-                | XD3_UNIMPLEMENTED => {
-                    return None;
+                Step::NeedSource(blkno) => {
+                    ready!(poll_getblk(cx, &mut self.codec, &mut self.src, blkno))?;
                 }
             }
+            self.step = None;
         }
     }
 
-    out.flush().await.ok()
+    /// Hands the buffered input to xdelta3; with nothing buffered, this ends the input.
+    fn submit(&mut self) {
+        self.codec.provide_input(self.filled);
+        self.filled = 0;
+        self.step = None;
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_drive(cx))?;
+        if self.step == Some(Step::Done) {
+            return Poll::Ready(Err(io::Error::other("write after the stream was finished")));
+        }
+        if self.filled == self.codec.input_buf().len() {
+            self.submit();
+            ready!(self.poll_drive(cx))?;
+        }
+
+        let space = &mut self.codec.input_buf()[self.filled..];
+        let len = space.len().min(buf.len());
+        space[..len].copy_from_slice(&buf[..len]);
+        self.filled += len;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drive(cx))?;
+        Pin::new(&mut self.out).poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_drive(cx))?;
+            if self.step != Some(Step::NeedInput) {
+                break;
+            }
+            self.submit();
+        }
+        Pin::new(&mut self.out).poll_close(cx)
+    }
+}
+
+/// Writer that generates a patch from the data written to it
+///
+/// This is a push-based [`encode_async`]: the updated data is written to the
+/// `EncoderWriter`, the original data is read from `src` as needed, and the patch is
+/// written to `out`. Input is handed to xdelta3 a window at a time, so the patch lags
+/// behind the input.
+///
+/// The writer must be closed once all the data has been written; the end of the patch is
+/// only written then. Closing it also closes `out`.
+///
+/// ```
+/// use futures::io::AsyncWriteExt;
+/// use xdelta3::stream::EncoderWriter;
+///
+/// futures::executor::block_on(async {
+///     let src: &[u8] = &[1, 2, 4, 4, 7, 6, 7];
+///     let mut patch = Vec::new();
+///     let mut writer = EncoderWriter::new(src, &mut patch).unwrap();
+///     writer.write_all(&[1, 2, 3, 4, 5, 6, 7]).await.unwrap();
+///     writer.close().await.unwrap();
+///     drop(writer);
+///     assert_eq!(xdelta3::decode(&patch, src).unwrap(), [1, 2, 3, 4, 5, 6, 7]);
+/// });
+/// ```
+pub struct EncoderWriter<S, W> {
+    inner: CodecWriter<S, W>,
+}
+
+impl<S, W> EncoderWriter<S, W>
+where
+    S: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Creates a writer of the patch from `src` to the data written, into `out`.
+    pub fn new(src: S, out: W) -> Result<Self> {
+        Self::with_config(src, out, &EncoderConfig::default())
+    }
+
+    /// Creates a writer like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(src: S, out: W, config: &EncoderConfig) -> Result<Self> {
+        let inner = CodecWriter::new(Mode::Encode, config.settings()?, src, out)?;
+        Ok(Self { inner })
+    }
+
+    /// Returns the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner.out
+    }
+}
+
+impl<S, W> AsyncWrite for EncoderWriter<S, W>
+where
+    S: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().inner.poll_write(cx, buf)
+    }
+
+    /// Flushes the inner writer. Input still buffered for the current window is not
+    /// encoded until the window is full or the writer is closed.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.poll_close(cx)
+    }
+}
+
+/// Writer that applies the patch written to it
+///
+/// This is a push-based [`decode_async`]: the patch is written to the `DecoderWriter`, the
+/// original data is read from `src` as needed, and the patched data is written to `out`.
+///
+/// The writer must be closed once the whole patch has been written; a patch that ends in
+/// the middle of a window is only reported then. Closing it also closes `out`.
+pub struct DecoderWriter<S, W> {
+    inner: CodecWriter<S, W>,
+}
+
+impl<S, W> DecoderWriter<S, W>
+where
+    S: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Creates a writer that applies the patch written to it to `src`, into `out`.
+    pub fn new(src: S, out: W) -> Result<Self> {
+        Self::with_config(src, out, &DecoderConfig::default())
+    }
+
+    /// Creates a writer like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(src: S, out: W, config: &DecoderConfig) -> Result<Self> {
+        let inner = CodecWriter::new(Mode::Decode, config.settings()?, src, out)?;
+        Ok(Self { inner })
+    }
+
+    /// Returns the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner.out
+    }
+}
+
+impl<S, W> AsyncWrite for DecoderWriter<S, W>
+where
+    S: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().inner.poll_write(cx, buf)
+    }
+
+    /// Flushes the inner writer. The part of the patch still buffered is not decoded
+    /// until the buffer is full or the writer is closed.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::noise;
+    use std::os::raw::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Allocations made by xdelta3 through `counting_alloc` and `counting_free`.
+    #[derive(Default)]
+    struct Allocs {
+        allocs: AtomicUsize,
+        frees: AtomicUsize,
+    }
+
+    unsafe extern "C" fn counting_alloc(
+        opaque: *mut c_void,
+        items: binding::usize_t,
+        size: binding::usize_t,
+    ) -> *mut c_void {
+        let ptr = libc::malloc(items as usize * size as usize);
+        if !ptr.is_null() {
+            (*(opaque as *const Allocs))
+                .allocs
+                .fetch_add(1, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe extern "C" fn counting_free(opaque: *mut c_void, ptr: *mut c_void) {
+        if !ptr.is_null() {
+            (*(opaque as *const Allocs))
+                .frees
+                .fetch_add(1, Ordering::SeqCst);
+        }
+        libc::free(ptr);
+    }
+
+    /// A source of known length that never has a block ready.
+    struct Stalled(u64);
+
+    impl Source for Stalled {
+        fn total_len(&self) -> Option<u64> {
+            Some(self.0)
+        }
+
+        fn poll_block(
+            &mut self,
+            _cx: &mut Context<'_>,
+            _blkno: u64,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn drop_frees_stream() {
+        let src = noise(1 << 20, 1);
+        let mut input = src.clone();
+        input[1000..1100].copy_from_slice(&noise(100, 2));
+        let patch = crate::encode(&input, &src).expect("failed to encode");
+
+        let counts = Allocs::default();
+        let config = DecoderConfig::new();
+        let mut settings = config.settings().unwrap();
+        settings.xd3.alloc = Some(counting_alloc);
+        settings.xd3.freef = Some(counting_free);
+        settings.xd3.opaque = &counts as *const Allocs as *mut c_void;
+        let codec = Codec::new(Mode::Decode, settings, true);
+        let src = Some(Blocks::new(Stalled(src.len() as u64)));
+        let mut out = Vec::new();
+        let mut decode = Box::pin(process_async(codec, &patch[..], src, &mut out, None));
+
+        // The first window asks for a source block, which never comes, so the stream is
+        // left in the middle of the window.
+        let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+        assert!(decode.as_mut().poll(&mut cx).is_pending());
+        let allocs = counts.allocs.load(Ordering::SeqCst);
+        assert!(allocs > 0);
+        assert!(counts.frees.load(Ordering::SeqCst) < allocs);
+
+        drop(decode);
+        assert_eq!(
+            counts.frees.load(Ordering::SeqCst),
+            counts.allocs.load(Ordering::SeqCst)
+        );
+    }
 }
@@ -0,0 +1,486 @@
+//! Streaming functions for tokio readers and writers.
+//!
+//! Each function here works like the function of the same name in the parent module, but
+//! takes [`tokio::io::AsyncRead`], [`tokio::io::AsyncWrite`] and [`tokio::io::AsyncSeek`]
+//! types. The readers and writers are adapted to the `futures-io` traits and run through
+//! the same code.
+//!
+//! Unlike the parent module, these functions run xdelta3 on tokio's blocking thread pool
+//! with [`spawn_blocking`](::tokio::task::spawn_blocking), while the reads and writes stay
+//! on the task. Encoding a whole window can take hundreds of milliseconds, and this keeps
+//! it from holding up the other tasks of the executor thread. The steps between two reads
+//! or writes go to the pool together, and handing back output already produced stays on
+//! the task. Outside a tokio runtime, the steps run in place.
+//!
+//! The memory-mapped functions copy each source block out of the map on the task instead
+//! of handing xdelta3 pointers into it: a step on the pool keeps running after its future
+//! is dropped, so it must not read from a map that is only borrowed.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use ::tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use ::tokio::runtime::Handle;
+use futures_util::ready;
+
+use super::{decoder, encoder, process_async, ReadSource, SeekSource, Stepped};
+use crate::binding;
+use crate::codec::{Codec, Step};
+use crate::source::Blocks;
+#[cfg(feature = "mmap")]
+use crate::MmapSource;
+use crate::{DecoderConfig, EncoderConfig, Error, Result, Source, Stats};
+
+/// Adapts a tokio reader, writer or seeker to the `futures-io` traits.
+struct Compat<T> {
+    inner: T,
+    /// Whether a seek has been started and not yet completed.
+    seeking: bool,
+}
+
+impl<T> Compat<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            seeking: false,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> futures_io::AsyncRead for Compat<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut read_buf = ReadBuf::new(buf);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read_buf))?;
+        Poll::Ready(Ok(read_buf.filled().len()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> futures_io::AsyncWrite for Compat<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: AsyncSeek + Unpin> futures_io::AsyncSeek for Compat<T> {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: io::SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        if !self.seeking {
+            Pin::new(&mut self.inner).start_seek(pos)?;
+            self.seeking = true;
+        }
+        let result = ready!(Pin::new(&mut self.inner).poll_complete(cx));
+        self.seeking = false;
+        Poll::Ready(result)
+    }
+}
+
+/// Runs codec steps on the blocking thread pool of the current runtime, up to the first
+/// step that needs I/O.
+///
+/// Steps that only hand back output left over from the last step run in place, as do
+/// all steps outside a runtime.
+fn spawn_step(mut codec: Codec) -> Stepped {
+    let handle = match Handle::try_current() {
+        Ok(handle) if codec.output().is_empty() => handle,
+        _ => {
+            let step = codec.step();
+            return Box::pin(std::future::ready(step.map(|step| (codec, step))));
+        }
+    };
+    let task = handle.spawn_blocking(move || loop {
+        match codec.step()? {
+            Step::GotHeader | Step::WindowStart | Step::WindowFinish => {}
+            step => return Ok((codec, step)),
+        }
+    });
+    Box::pin(async move {
+        match task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // The runtime is shutting down.
+            Err(e) => Err(Error::Internal {
+                code: binding::xd3_rvalues::XD3_INTERNAL as i32,
+                msg: format!("xdelta3 step did not run: {}", e),
+                position: None,
+            }),
+        }
+    })
+}
+
+/// Function to decode the difference data from a tokio stream
+///
+/// See [`decode_async`](super::decode_async).
+pub fn decode_async<R1, R2, W>(input: R1, src: R2, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data from a tokio stream with custom settings
+///
+/// See [`decode_async_with`](super::decode_async_with).
+pub fn decode_async_with<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        decoder(config, true),
+        Compat::new(input),
+        Some(Blocks::new(ReadSource::new(Compat::new(src)))),
+        Compat::new(out),
+        Some(spawn_step),
+    )
+}
+
+/// Function to decode the difference data against a seekable tokio source
+///
+/// See [`decode_async_seekable`](super::decode_async_seekable).
+pub fn decode_async_seekable<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_seekable_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data against a seekable tokio source with custom
+/// settings
+///
+/// See [`decode_async_seekable_with`](super::decode_async_seekable_with).
+pub fn decode_async_seekable_with<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let codec = decoder(config, true);
+    async move {
+        let src = SeekSource::new(Compat::new(src))
+            .await
+            .map_err(Error::Source)?;
+        process_async(
+            codec,
+            Compat::new(input),
+            Some(Blocks::new(src)),
+            Compat::new(out),
+            Some(spawn_step),
+        )
+        .await
+    }
+}
+
+/// Function to decode the difference data from a tokio stream against any [`Source`]
+///
+/// See [`decode_async_source`](super::decode_async_source).
+pub fn decode_async_source<R, S, W>(input: R, src: S, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_source_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data from a tokio stream against any [`Source`] with
+/// custom settings
+///
+/// See [`decode_async_source_with`](super::decode_async_source_with).
+pub fn decode_async_source_with<R, S, W>(
+    input: R,
+    src: S,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        decoder(config, true),
+        Compat::new(input),
+        Some(Blocks::new(src)),
+        Compat::new(out),
+        Some(spawn_step),
+    )
+}
+
+/// Function to decode the difference data from a tokio stream against a memory-mapped
+/// source
+///
+/// See [`decode_async_mmap`](super::decode_async_mmap).
+///
+/// Source blocks are copied out of the map, so that the steps can run on the blocking
+/// pool. The process is killed with `SIGBUS` if the file is truncated while a block is
+/// copied.
+#[cfg(feature = "mmap")]
+pub async fn decode_async_mmap<R, W>(input: R, src: &MmapSource, out: W) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decode_async_mmap_with(input, src, out, &DecoderConfig::default()).await
+}
+
+/// Function to decode the difference data from a tokio stream against a memory-mapped
+/// source with custom settings
+///
+/// See [`decode_async_mmap_with`](super::decode_async_mmap_with).
+///
+/// Source blocks are copied out of the map, so that the steps can run on the blocking
+/// pool. The process is killed with `SIGBUS` if the file is truncated while a block is
+/// copied.
+#[cfg(feature = "mmap")]
+pub async fn decode_async_mmap_with<R, W>(
+    input: R,
+    src: &MmapSource,
+    out: W,
+    config: &DecoderConfig,
+) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    src.check().map_err(Error::Source)?;
+    process_async(
+        decoder(config, true),
+        Compat::new(input),
+        Some(Blocks::new(src)),
+        Compat::new(out),
+        Some(spawn_step),
+    )
+    .await
+}
+
+/// Function to generate the difference data from a tokio stream
+///
+/// See [`encode_async`](super::encode_async).
+pub fn encode_async<R1, R2, W>(input: R1, src: R2, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    encode_async_with(input, src, out, &EncoderConfig::default())
+}
+
+/// Function to generate the difference data from a tokio stream with custom settings
+///
+/// See [`encode_async_with`](super::encode_async_with).
+pub fn encode_async_with<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &EncoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R1: AsyncRead + Unpin,
+    R2: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        encoder(config, true),
+        Compat::new(input),
+        Some(Blocks::new(ReadSource::new(Compat::new(src)))),
+        Compat::new(out),
+        Some(spawn_step),
+    )
+}
+
+/// Function to generate the difference data from a tokio stream against any [`Source`]
+///
+/// See [`encode_async_source`](super::encode_async_source).
+pub fn encode_async_source<R, S, W>(input: R, src: S, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    encode_async_source_with(input, src, out, &EncoderConfig::default())
+}
+
+/// Function to generate the difference data from a tokio stream against any [`Source`]
+/// with custom settings
+///
+/// See [`encode_async_source_with`](super::encode_async_source_with).
+pub fn encode_async_source_with<R, S, W>(
+    input: R,
+    src: S,
+    out: W,
+    config: &EncoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    S: Source,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        encoder(config, true),
+        Compat::new(input),
+        Some(Blocks::new(src)),
+        Compat::new(out),
+        Some(spawn_step),
+    )
+}
+
+/// Function to generate the difference data from a tokio stream against a memory-mapped
+/// source
+///
+/// See [`encode_async_mmap`](super::encode_async_mmap).
+///
+/// Source blocks are copied out of the map, so that the steps can run on the blocking
+/// pool. The process is killed with `SIGBUS` if the file is truncated while a block is
+/// copied.
+#[cfg(feature = "mmap")]
+pub async fn encode_async_mmap<R, W>(input: R, src: &MmapSource, out: W) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    encode_async_mmap_with(input, src, out, &EncoderConfig::default()).await
+}
+
+/// Function to generate the difference data from a tokio stream against a memory-mapped
+/// source with custom settings
+///
+/// See [`encode_async_mmap_with`](super::encode_async_mmap_with).
+///
+/// Source blocks are copied out of the map, so that the steps can run on the blocking
+/// pool. The process is killed with `SIGBUS` if the file is truncated while a block is
+/// copied.
+#[cfg(feature = "mmap")]
+pub async fn encode_async_mmap_with<R, W>(
+    input: R,
+    src: &MmapSource,
+    out: W,
+    config: &EncoderConfig,
+) -> Result<Stats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    src.check().map_err(Error::Source)?;
+    process_async(
+        encoder(config, true),
+        Compat::new(input),
+        Some(Blocks::new(src)),
+        Compat::new(out),
+        Some(spawn_step),
+    )
+    .await
+}
+
+/// Function to compress a tokio stream without a source
+///
+/// See [`compress_async`](super::compress_async).
+pub fn compress_async<R, W>(input: R, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    compress_async_with(input, out, &EncoderConfig::default())
+}
+
+/// Function to compress a tokio stream without a source, with custom settings
+///
+/// See [`compress_async_with`](super::compress_async_with).
+pub fn compress_async_with<R, W>(
+    input: R,
+    out: W,
+    config: &EncoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        encoder(config, false),
+        Compat::new(input),
+        None::<Blocks<&[u8]>>,
+        Compat::new(out),
+        Some(spawn_step),
+    )
+}
+
+/// Function to decompress a tokio stream made by [`compress_async`]
+///
+/// See [`decompress_async`](super::decompress_async).
+pub fn decompress_async<R, W>(input: R, out: W) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    decompress_async_with(input, out, &DecoderConfig::default())
+}
+
+/// Function to decompress a tokio stream made by [`compress_async`], with custom settings
+///
+/// See [`decompress_async_with`](super::decompress_async_with).
+pub fn decompress_async_with<R, W>(
+    input: R,
+    out: W,
+    config: &DecoderConfig,
+) -> impl Future<Output = Result<Stats>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    process_async(
+        decoder(config, false),
+        Compat::new(input),
+        None::<Blocks<&[u8]>>,
+        Compat::new(out),
+        Some(spawn_step),
+    )
+}
+
+/// Function to read the application header of a patch from a tokio stream
+///
+/// See [`app_header_async`](super::app_header_async).
+pub async fn app_header_async<R>(input: R) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    super::app_header_async(Compat::new(input)).await
+}
@@ -0,0 +1,911 @@
+//! Blocking streaming API
+//!
+//! These functions work like the ones in the `stream` module, but read from
+//! [`std::io::Read`] and write to [`std::io::Write`], so no executor is needed. They are
+//! available without any cargo feature.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use super::binding;
+use super::codec::{Codec, Step};
+use super::config::Settings;
+use super::source::{
+    now, out_of_order, poll_fill_block, poll_getblk, poll_start_source, BlockSource, Blocks,
+};
+use super::vcdiff;
+use super::xd3::{Mode, Xd3Stream};
+#[cfg(feature = "mmap")]
+use super::MmapSource;
+use super::{DecoderConfig, EncoderConfig, Error, Result, Source, Stats};
+use log::debug;
+
+/// A source read front to back from a reader
+///
+/// Short reads are fine: each block is read until it is full or the reader ends.
+pub struct ReadSource<R> {
+    read: R,
+    /// Number of the block to be read next.
+    next: u64,
+}
+
+impl<R: Read> ReadSource<R> {
+    /// Reads the source from `read`.
+    pub fn new(read: R) -> Self {
+        Self { read, next: 0 }
+    }
+}
+
+impl<R: Read> Source for ReadSource<R> {
+    fn total_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    fn poll_block(
+        &mut self,
+        _cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if blkno != self.next {
+            return Poll::Ready(Err(out_of_order(blkno)));
+        }
+        let read = &mut self.read;
+        let len = ready!(poll_fill_block(&mut 0, buf, |_, buf| Poll::Ready(
+            read.read(buf)
+        )))?;
+        self.next += 1;
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// A source read from a seekable reader, at whatever block is needed
+///
+/// The source starts at the position the reader had when it was passed in, and ends at
+/// the end of the reader.
+pub struct SeekSource<R> {
+    read: R,
+    /// Offset of the start of the source in `read`.
+    base: u64,
+    /// Current offset of `read`.
+    pos: u64,
+    len: u64,
+}
+
+impl<R: Read + Seek> SeekSource<R> {
+    /// Reads the source from `read`, starting at its current position.
+    pub fn new(mut read: R) -> io::Result<Self> {
+        let base = read.stream_position()?;
+        let end = read.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            read,
+            base,
+            pos: end,
+            len: end.saturating_sub(base),
+        })
+    }
+}
+
+impl<R: Read + Seek> Source for SeekSource<R> {
+    fn total_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+
+    fn poll_block(
+        &mut self,
+        _cx: &mut Context<'_>,
+        blkno: u64,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let start = self.base + blkno * buf.len() as u64;
+        let (read, pos) = (&mut self.read, &mut self.pos);
+        poll_fill_block(&mut 0, buf, |offset, buf| {
+            let target = start + offset as u64;
+            if *pos != target {
+                *pos = read.seek(SeekFrom::Start(target))?;
+            }
+            let read_len = read.read(buf)?;
+            *pos += read_len as u64;
+            Poll::Ready(Ok(read_len))
+        })
+    }
+}
+
+/// Reads the first source window, unless the source length is already known, and
+/// attaches the source to the stream.
+fn start_source<S: BlockSource>(codec: &mut Codec, src: &mut S) -> Result<()> {
+    now(|cx| poll_start_source(cx, codec, src))
+}
+
+/// Answers xdelta3's request for source block `blkno`.
+fn getblk<S: BlockSource>(codec: &mut Codec, src: &mut S, blkno: usize) -> Result<()> {
+    now(|cx| poll_getblk(cx, codec, src, blkno))
+}
+
+/// Function to decode the difference data from a stream
+///
+/// This is the blocking counterpart of [`decode`](crate::decode). The patch is read from
+/// `input`, the original data from `src`, and the patched data is written to `out`.
+/// On success, the [`Stats`] of the decode are returned.
+pub fn decode<R1, R2, W>(input: R1, src: R2, out: W) -> Result<Stats>
+where
+    R1: Read,
+    R2: Read,
+    W: Write,
+{
+    decode_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data from a stream with custom settings
+///
+/// This works like [`decode`], with the settings taken from `config`.
+pub fn decode_with<R1, R2, W>(input: R1, src: R2, out: W, config: &DecoderConfig) -> Result<Stats>
+where
+    R1: Read,
+    R2: Read,
+    W: Write,
+{
+    decode_source_with(input, ReadSource::new(src), out, config)
+}
+
+/// Function to decode the difference data against a seekable source
+///
+/// This works like [`decode`], but blocks of `src` that no longer fit in the source
+/// window are read again when the patch copies from them, so any valid patch can be
+/// applied. The source starts at the current position of `src`.
+pub fn decode_seekable<R1, R2, W>(input: R1, src: R2, out: W) -> Result<Stats>
+where
+    R1: Read,
+    R2: Read + Seek,
+    W: Write,
+{
+    decode_seekable_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data against a seekable source with custom settings
+///
+/// This works like [`decode_seekable`], with the settings taken from `config`.
+pub fn decode_seekable_with<R1, R2, W>(
+    input: R1,
+    src: R2,
+    out: W,
+    config: &DecoderConfig,
+) -> Result<Stats>
+where
+    R1: Read,
+    R2: Read + Seek,
+    W: Write,
+{
+    let src = SeekSource::new(src).map_err(Error::Source)?;
+    decode_source_with(input, src, out, config)
+}
+
+/// Function to decode the difference data against any [`Source`]
+///
+/// This works like [`decode`], with the original data read from `src` a block at a time.
+/// `src` must never return `Poll::Pending`: this has no way to wait, and fails with an
+/// [`Error::Source`] of kind `WouldBlock` instead.
+pub fn decode_source<R, S, W>(input: R, src: S, out: W) -> Result<Stats>
+where
+    R: Read,
+    S: Source,
+    W: Write,
+{
+    decode_source_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data against any [`Source`] with custom settings
+///
+/// This works like [`decode_source`], with the settings taken from `config`.
+pub fn decode_source_with<R, S, W>(
+    input: R,
+    src: S,
+    out: W,
+    config: &DecoderConfig,
+) -> Result<Stats>
+where
+    R: Read,
+    S: Source,
+    W: Write,
+{
+    let settings = config.settings()?;
+    process(Mode::Decode, settings, input, Some(Blocks::new(src)), out)
+}
+
+/// Function to decode the difference data against a memory-mapped source
+///
+/// This works like [`decode`], but source blocks are read straight from the map without
+/// being copied, and the patch may copy from anywhere in the source.
+///
+/// A file already truncated when this starts is reported as an error, but one truncated
+/// later makes the operating system kill the process with `SIGBUS`. Read files that can
+/// change with [`decode_source`] and a [`File`](std::fs::File) instead.
+#[cfg(feature = "mmap")]
+pub fn decode_mmap<R, W>(input: R, src: &MmapSource, out: W) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    decode_mmap_with(input, src, out, &DecoderConfig::default())
+}
+
+/// Function to decode the difference data against a memory-mapped source with custom settings
+///
+/// This works like [`decode_mmap`], with the settings taken from `config`.
+///
+/// Like [`decode_mmap`], this kills the process with `SIGBUS` if the file is truncated while
+/// xdelta3 reads from the map.
+#[cfg(feature = "mmap")]
+pub fn decode_mmap_with<R, W>(
+    input: R,
+    src: &MmapSource,
+    out: W,
+    config: &DecoderConfig,
+) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    let settings = config.settings()?;
+    process(Mode::Decode, settings, input, Some(src), out)
+}
+
+/// Function to generate the difference data from a stream
+///
+/// This is the blocking counterpart of [`encode`](crate::encode). The updated data is read
+/// from `input`, the original data from `src`, and the patch is written to `out`.
+/// On success, the [`Stats`] of the encode are returned.
+pub fn encode<R1, R2, W>(input: R1, src: R2, out: W) -> Result<Stats>
+where
+    R1: Read,
+    R2: Read,
+    W: Write,
+{
+    encode_with(input, src, out, &EncoderConfig::default())
+}
+
+/// Function to generate the difference data from a stream with custom settings
+///
+/// This works like [`encode`], with the compression level, matcher and buffer
+/// sizes taken from `config`.
+pub fn encode_with<R1, R2, W>(input: R1, src: R2, out: W, config: &EncoderConfig) -> Result<Stats>
+where
+    R1: Read,
+    R2: Read,
+    W: Write,
+{
+    encode_source_with(input, ReadSource::new(src), out, config)
+}
+
+/// Function to generate the difference data against any [`Source`]
+///
+/// This works like [`encode`], with the original data read from `src` a block at a time.
+/// `src` must never return `Poll::Pending`: this has no way to wait, and fails with an
+/// [`Error::Source`] of kind `WouldBlock` instead.
+pub fn encode_source<R, S, W>(input: R, src: S, out: W) -> Result<Stats>
+where
+    R: Read,
+    S: Source,
+    W: Write,
+{
+    encode_source_with(input, src, out, &EncoderConfig::default())
+}
+
+/// Function to generate the difference data against any [`Source`] with custom settings
+///
+/// This works like [`encode_source`], with the compression level, matcher and buffer
+/// sizes taken from `config`.
+pub fn encode_source_with<R, S, W>(
+    input: R,
+    src: S,
+    out: W,
+    config: &EncoderConfig,
+) -> Result<Stats>
+where
+    R: Read,
+    S: Source,
+    W: Write,
+{
+    let settings = config.settings()?;
+    process(Mode::Encode, settings, input, Some(Blocks::new(src)), out)
+}
+
+/// Function to generate the difference data against a memory-mapped source
+///
+/// This works like [`encode`], but source blocks are read straight from the map without
+/// being copied.
+///
+/// A file already truncated when this starts is reported as an error, but one truncated
+/// later makes the operating system kill the process with `SIGBUS`. Read files that can
+/// change with [`encode_source`] and a [`File`](std::fs::File) instead.
+#[cfg(feature = "mmap")]
+pub fn encode_mmap<R, W>(input: R, src: &MmapSource, out: W) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    encode_mmap_with(input, src, out, &EncoderConfig::default())
+}
+
+/// Function to generate the difference data against a memory-mapped source with custom
+/// settings
+///
+/// This works like [`encode_mmap`], with the settings taken from `config`.
+///
+/// Like [`encode_mmap`], this kills the process with `SIGBUS` if the file is truncated while
+/// xdelta3 reads from the map.
+#[cfg(feature = "mmap")]
+pub fn encode_mmap_with<R, W>(
+    input: R,
+    src: &MmapSource,
+    out: W,
+    config: &EncoderConfig,
+) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    let settings = config.settings()?;
+    process(Mode::Encode, settings, input, Some(src), out)
+}
+
+/// Function to compress a stream without a source
+///
+/// This is the blocking counterpart of [`compress`](crate::compress). The data is read
+/// from `input` and the patch is written to `out`.
+pub fn compress<R, W>(input: R, out: W) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    compress_with(input, out, &EncoderConfig::default())
+}
+
+/// Function to compress a stream without a source, with custom settings
+///
+/// This works like [`compress`], with the settings taken from `config`.
+pub fn compress_with<R, W>(input: R, out: W, config: &EncoderConfig) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    let settings = config.settings()?;
+    process(Mode::Encode, settings, input, None::<Blocks<&[u8]>>, out)
+}
+
+/// Function to decompress a stream made by [`compress`]
+///
+/// This is the blocking counterpart of [`decompress`](crate::decompress).
+pub fn decompress<R, W>(input: R, out: W) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    decompress_with(input, out, &DecoderConfig::default())
+}
+
+/// Function to decompress a stream made by [`compress`], with custom settings
+///
+/// This works like [`decompress`], with the settings taken from `config`.
+pub fn decompress_with<R, W>(input: R, out: W, config: &DecoderConfig) -> Result<Stats>
+where
+    R: Read,
+    W: Write,
+{
+    let settings = config.settings()?;
+    process(Mode::Decode, settings, input, None::<Blocks<&[u8]>>, out)
+}
+
+/// Function to read the application header of a patch from a stream
+///
+/// This is the blocking counterpart of [`app_header`](crate::app_header). `input` is read
+/// up to the end of the patch header, and possibly a little further.
+pub fn app_header<R>(mut input: R) -> Result<Option<Vec<u8>>>
+where
+    R: Read,
+{
+    let mut stream = Xd3Stream::new();
+    let mut cfg: binding::xd3_config = unsafe { std::mem::zeroed() };
+    stream.config(&mut cfg)?;
+
+    let mut input_buf = vec![0u8; 4096];
+    loop {
+        use binding::xd3_rvalues::*;
+        match stream.step(Mode::Decode)? {
+            XD3_GOTHEADER => return Ok(stream.app_header().map(<[u8]>::to_vec)),
+            XD3_INPUT => {
+                let read_size = input.read(&mut input_buf).map_err(Error::Input)?;
+                if read_size == 0 {
+                    return Err(Error::InvalidInput {
+                        msg: "patch ends inside its header".to_owned(),
+                        position: Some(stream.position()),
+                    });
+                }
+                stream.avail_input(&input_buf[..read_size]);
+            }
+            _ => {
+                // nothing else happens before the header is complete
+            }
+        }
+    }
+}
+
+/// Function to read the length of the target from the window headers of a seekable patch
+///
+/// This is the blocking counterpart of [`target_len`](crate::target_len). Window bodies
+/// are skipped with seeks, and `patch` is left at the position it had when it was passed
+/// in. `Ok(None)` is returned if the headers cannot be read. The length can be passed to
+/// [`DecoderConfig::total_target_len`] so that the decode reports it as progress.
+///
+/// ```
+/// use std::io::Cursor;
+/// use xdelta3::{sync, DecoderConfig};
+///
+/// let src: &[u8] = &[1, 2, 4, 4, 7, 6, 7];
+/// let mut patch = Cursor::new(xdelta3::encode(&[1, 2, 3, 4, 5, 6, 7], src).unwrap());
+/// let len = sync::target_len(&mut patch).unwrap().unwrap();
+/// let config = DecoderConfig::new().total_target_len(len);
+/// let mut out = Vec::new();
+/// sync::decode_with(patch, src, &mut out, &config).unwrap();
+/// assert_eq!(out.len() as u64, len);
+/// ```
+pub fn target_len<R>(patch: &mut R) -> Result<Option<u64>>
+where
+    R: Read + Seek,
+{
+    let start = patch.stream_position().map_err(Error::Input)?;
+    let end = patch.seek(SeekFrom::End(0)).map_err(Error::Input)?;
+    patch.seek(SeekFrom::Start(start)).map_err(Error::Input)?;
+
+    let mut headers = SeekBytes {
+        reader: io::BufReader::new(&mut *patch),
+        pos: start,
+        end,
+        error: None,
+    };
+    let len = vcdiff::read_target_len(&mut headers);
+    if let Some(e) = headers.error {
+        return Err(Error::Input(e));
+    }
+    patch.seek(SeekFrom::Start(start)).map_err(Error::Input)?;
+    Ok(len)
+}
+
+/// Patch headers read from a seekable reader, for [`target_len`].
+struct SeekBytes<R> {
+    reader: io::BufReader<R>,
+    pos: u64,
+    end: u64,
+    /// First I/O error, which ends the headers.
+    error: Option<io::Error>,
+}
+
+impl<R: Read + Seek> vcdiff::Bytes for SeekBytes<R> {
+    fn byte(&mut self) -> Option<u8> {
+        let mut b = [0u8];
+        match self.reader.read_exact(&mut b) {
+            Ok(()) => {
+                self.pos += 1;
+                Some(b[0])
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn skip(&mut self, len: u64) -> Option<()> {
+        let pos = self.pos.checked_add(len).filter(|&pos| pos <= self.end)?;
+        if let Err(e) = self.reader.seek_relative(i64::try_from(len).ok()?) {
+            self.error = Some(e);
+            return None;
+        }
+        self.pos = pos;
+        Some(())
+    }
+}
+
+fn process<R, S, W>(
+    mode: Mode,
+    settings: Settings<'_>,
+    mut input: R,
+    mut src: Option<S>,
+    mut out: W,
+) -> Result<Stats>
+where
+    R: Read,
+    S: BlockSource,
+    W: Write,
+{
+    let mut codec = Codec::new(mode, settings, src.is_some())?;
+    let mut io_time = Duration::default();
+    let result = run(&mut codec, &mut input, &mut src, &mut out, &mut io_time);
+
+    // Flush even on error, so that a cancelled run leaves its complete windows in `out`.
+    let started = Instant::now();
+    let flushed = out.flush().map_err(Error::Output);
+    io_time += started.elapsed();
+    match result {
+        Ok(()) => flushed.map(|()| codec.stats(io_time)),
+        Err(e @ Error::Cancelled { .. }) => flushed.and(Err(e)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Steps `codec` to the end of its input, reading and writing as it asks.
+fn run<R, S, W>(
+    codec: &mut Codec,
+    input: &mut R,
+    src: &mut Option<S>,
+    out: &mut W,
+    io_time: &mut Duration,
+) -> Result<()>
+where
+    R: Read,
+    S: BlockSource,
+    W: Write,
+{
+    if let Some(read) = src {
+        let started = Instant::now();
+        start_source(codec, read)?;
+        *io_time += started.elapsed();
+    }
+
+    loop {
+        match codec.step()? {
+            Step::NeedInput => {
+                let started = Instant::now();
+                let read_size = input.read(codec.input_buf()).map_err(|e| {
+                    debug!("error on read: {:?}", e);
+                    Error::Input(e)
+                })?;
+                *io_time += started.elapsed();
+                codec.provide_input(read_size);
+            }
+            Step::Output => {
+                let started = Instant::now();
+                out.write_all(codec.output()).map_err(|e| {
+                    debug!("error on write: {:?}", e);
+                    Error::Output(e)
+                })?;
+                *io_time += started.elapsed();
+                let len = codec.output().len();
+                codec.consume_output(len);
+            }
+            Step::NeedSource(blkno) => {
+                if let Some(read) = src {
+                    let started = Instant::now();
+                    getblk(codec, read, blkno)?;
+                    *io_time += started.elapsed();
+                }
+            }
+            Step::GotHeader | Step::WindowStart | Step::WindowFinish => {}
+            Step::Done => return Ok(()),
+        }
+    }
+}
+
+/// Shared state of [`EncoderReader`] and [`DecoderReader`].
+struct CodecReader<R, S> {
+    codec: Codec,
+    input: R,
+    src: Blocks<ReadSource<S>>,
+    started: bool,
+    /// The step being served, kept if the reader returns an error in the middle of it.
+    step: Option<Step>,
+}
+
+impl<R: Read, S: Read> CodecReader<R, S> {
+    fn new(mode: Mode, settings: Settings<'_>, input: R, src: S) -> Result<Self> {
+        Ok(Self {
+            codec: Codec::new(mode, settings, true)?,
+            input,
+            src: Blocks::new(ReadSource::new(src)),
+            started: false,
+            step: None,
+        })
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.started {
+            start_source(&mut self.codec, &mut self.src)?;
+            self.started = true;
+        }
+
+        loop {
+            let step = match self.step {
+                Some(step) => step,
+                None => self.codec.step()?,
+            };
+            self.step = Some(step);
+
+            match step {
+                Step::NeedInput => {
+                    let read_size = self
+                        .input
+                        .read(self.codec.input_buf())
+                        .map_err(Error::Input)?;
+                    self.codec.provide_input(read_size);
+                }
+                Step::Output => {
+                    let out = self.codec.output();
+                    let len = out.len().min(buf.len());
+                    buf[..len].copy_from_slice(&out[..len]);
+                    self.codec.consume_output(len);
+                    self.step = None;
+                    return Ok(len);
+                }
+                Step::NeedSource(blkno) => getblk(&mut self.codec, &mut self.src, blkno)?,
+                Step::GotHeader | Step::WindowStart | Step::WindowFinish => {}
+                Step::Done => return Ok(0),
+            }
+            self.step = None;
+        }
+    }
+}
+
+/// Reader of the patch generated from a stream
+///
+/// This is a pull-based [`encode`]: the patch is produced as it is read, and the updated
+/// data and the original data are read from `input` and `src` as needed.
+///
+/// ```
+/// use std::io::Read;
+/// use xdelta3::sync::EncoderReader;
+///
+/// let input: &[u8] = &[1, 2, 3, 4, 5, 6, 7];
+/// let src: &[u8] = &[1, 2, 4, 4, 7, 6, 7];
+/// let mut patch = Vec::new();
+/// let mut reader = EncoderReader::new(input, src).unwrap();
+/// reader.read_to_end(&mut patch).unwrap();
+/// assert_eq!(xdelta3::decode(&patch, src).unwrap(), input);
+/// ```
+pub struct EncoderReader<R, S> {
+    inner: CodecReader<R, S>,
+}
+
+impl<R: Read, S: Read> EncoderReader<R, S> {
+    /// Creates a reader of the patch that turns the data in `src` into the data in `input`.
+    pub fn new(input: R, src: S) -> Result<Self> {
+        Self::with_config(input, src, &EncoderConfig::default())
+    }
+
+    /// Creates a reader like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(input: R, src: S, config: &EncoderConfig) -> Result<Self> {
+        let inner = CodecReader::new(Mode::Encode, config.settings()?, input, src)?;
+        Ok(Self { inner })
+    }
+}
+
+impl<R: Read, S: Read> Read for EncoderReader<R, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.inner.read(buf)?)
+    }
+}
+
+/// Reader of the data decoded from a stream
+///
+/// This is a pull-based [`decode`]: the patched data is produced as it is read, and the
+/// patch and the original data are read from `input` and `src` as needed.
+pub struct DecoderReader<R, S> {
+    inner: CodecReader<R, S>,
+}
+
+impl<R: Read, S: Read> DecoderReader<R, S> {
+    /// Creates a reader of the data made by applying the patch in `input` to `src`.
+    pub fn new(input: R, src: S) -> Result<Self> {
+        Self::with_config(input, src, &DecoderConfig::default())
+    }
+
+    /// Creates a reader like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(input: R, src: S, config: &DecoderConfig) -> Result<Self> {
+        let inner = CodecReader::new(Mode::Decode, config.settings()?, input, src)?;
+        Ok(Self { inner })
+    }
+}
+
+impl<R: Read, S: Read> Read for DecoderReader<R, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.inner.read(buf)?)
+    }
+}
+
+/// Shared state of [`EncoderWriter`] and [`DecoderWriter`].
+struct CodecWriter<S, W> {
+    codec: Codec,
+    src: Blocks<ReadSource<S>>,
+    out: W,
+    started: bool,
+    /// Number of bytes written into the input buffer and not yet handed to xdelta3.
+    filled: usize,
+    /// The step being served; `NeedInput` while xdelta3 waits for the input buffer.
+    step: Option<Step>,
+}
+
+impl<S: Read, W: Write> CodecWriter<S, W> {
+    fn new(mode: Mode, settings: Settings<'_>, src: S, out: W) -> Result<Self> {
+        Ok(Self {
+            codec: Codec::new(mode, settings, true)?,
+            src: Blocks::new(ReadSource::new(src)),
+            out,
+            started: false,
+            filled: 0,
+            step: None,
+        })
+    }
+
+    /// Runs xdelta3 until it waits for input or is done.
+    fn drive(&mut self) -> Result<()> {
+        if !self.started {
+            start_source(&mut self.codec, &mut self.src)?;
+            self.started = true;
+        }
+
+        loop {
+            let step = match self.step {
+                Some(step) => step,
+                None => self.codec.step()?,
+            };
+            self.step = Some(step);
+
+            match step {
+                Step::GotHeader | Step::WindowStart | Step::WindowFinish => {}
+                Step::NeedInput | Step::Done => return Ok(()),
+                Step::Output => {
+                    self.out.write_all(self.codec.output()).map_err(|e| {
+                        debug!("error on write: {:?}", e);
+                        Error::Output(e)
+                    })?;
+                    let len = self.codec.output().len();
+                    self.codec.consume_output(len);
+                }
+                Step::NeedSource(blkno) => getblk(&mut self.codec, &mut self.src, blkno)?,
+            }
+            self.step = None;
+        }
+    }
+
+    /// Hands the buffered input to xdelta3; with nothing buffered, this ends the input.
+    fn submit(&mut self) -> Result<()> {
+        self.codec.provide_input(self.filled);
+        self.filled = 0;
+        self.step = None;
+        self.drive()
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.drive()?;
+        if self.step == Some(Step::Done) {
+            return Err(io::Error::other("write after the stream was finished"));
+        }
+        if self.filled == self.codec.input_buf().len() {
+            self.submit()?;
+        }
+
+        let space = &mut self.codec.input_buf()[self.filled..];
+        let len = space.len().min(buf.len());
+        space[..len].copy_from_slice(&buf[..len]);
+        self.filled += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drive()?;
+        self.out.flush()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.drive()?;
+        while self.step == Some(Step::NeedInput) {
+            self.submit()?;
+        }
+        self.out.flush().map_err(Error::Output)
+    }
+}
+
+/// Writer that generates a patch from the data written to it
+///
+/// This is a push-based [`encode`]: the updated data is written to the `EncoderWriter`, the
+/// original data is read from `src` as needed, and the patch is written to `out`. Input is
+/// handed to xdelta3 a window at a time, so the patch lags behind the input.
+///
+/// [`finish`](Self::finish) must be called once all the data has been written; the end of
+/// the patch is only written then.
+///
+/// ```
+/// use std::io::Write;
+/// use xdelta3::sync::EncoderWriter;
+///
+/// let src: &[u8] = &[1, 2, 4, 4, 7, 6, 7];
+/// let mut writer = EncoderWriter::new(src, Vec::new()).unwrap();
+/// writer.write_all(&[1, 2, 3, 4, 5, 6, 7]).unwrap();
+/// let patch = writer.finish().unwrap();
+/// assert_eq!(xdelta3::decode(&patch, src).unwrap(), [1, 2, 3, 4, 5, 6, 7]);
+/// ```
+pub struct EncoderWriter<S, W> {
+    inner: CodecWriter<S, W>,
+}
+
+impl<S: Read, W: Write> EncoderWriter<S, W> {
+    /// Creates a writer of the patch from `src` to the data written, into `out`.
+    pub fn new(src: S, out: W) -> Result<Self> {
+        Self::with_config(src, out, &EncoderConfig::default())
+    }
+
+    /// Creates a writer like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(src: S, out: W, config: &EncoderConfig) -> Result<Self> {
+        let inner = CodecWriter::new(Mode::Encode, config.settings()?, src, out)?;
+        Ok(Self { inner })
+    }
+
+    /// Ends the input, writes the rest of the patch and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.finish()?;
+        Ok(self.inner.out)
+    }
+}
+
+impl<S: Read, W: Write> Write for EncoderWriter<S, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    /// Flushes the inner writer. Input still buffered for the current window is not
+    /// encoded until the window is full or [`finish`](Self::finish) is called.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer that applies the patch written to it
+///
+/// This is a push-based [`decode`]: the patch is written to the `DecoderWriter`, the
+/// original data is read from `src` as needed, and the patched data is written to `out`.
+///
+/// [`finish`](Self::finish) must be called once the whole patch has been written; a patch
+/// that ends in the middle of a window is only reported then.
+pub struct DecoderWriter<S, W> {
+    inner: CodecWriter<S, W>,
+}
+
+impl<S: Read, W: Write> DecoderWriter<S, W> {
+    /// Creates a writer that applies the patch written to it to `src`, into `out`.
+    pub fn new(src: S, out: W) -> Result<Self> {
+        Self::with_config(src, out, &DecoderConfig::default())
+    }
+
+    /// Creates a writer like [`new`](Self::new), with the settings taken from `config`.
+    pub fn with_config(src: S, out: W, config: &DecoderConfig) -> Result<Self> {
+        let inner = CodecWriter::new(Mode::Decode, config.settings()?, src, out)?;
+        Ok(Self { inner })
+    }
+
+    /// Ends the patch, writes the rest of the patched data and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.finish()?;
+        Ok(self.inner.out)
+    }
+}
+
+impl<S: Read, W: Write> Write for DecoderWriter<S, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    /// Flushes the inner writer. The part of the patch still buffered is not decoded
+    /// until the buffer is full or [`finish`](Self::finish) is called.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,128 @@
+//! Minimal reader for the VCDIFF (RFC 3284) headers written by xdelta3.
+//!
+//! Only the file header and the window headers are parsed; the window sections are skipped.
+
+use std::convert::TryFrom;
+
+use super::xd3::XD3_HARDMAXWINSIZE;
+
+const VCDIFF_MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+
+// Header indicator bits.
+const VCD_SECONDARY: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// Window indicator bits.
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+
+// xdelta3 rejects target windows larger than this.
+const MAX_TARGET_WINDOW: u64 = XD3_HARDMAXWINSIZE as u64;
+
+/// Bytes the headers are read from.
+pub(crate) trait Bytes {
+    /// Reads the next byte, or returns `None` at the end.
+    fn byte(&mut self) -> Option<u8>;
+
+    /// Skips `len` bytes, or returns `None` if fewer are left.
+    fn skip(&mut self, len: u64) -> Option<()>;
+
+    /// Reads a variable-length integer: base 128, most significant digit first.
+    ///
+    /// Returns the value and the number of bytes it took.
+    fn varint(&mut self) -> Option<(u64, u64)> {
+        let mut value = 0u64;
+        let mut len = 0;
+        loop {
+            let b = self.byte()?;
+            len += 1;
+            if value > (u64::MAX >> 7) {
+                return None;
+            }
+            value = (value << 7) | u64::from(b & 0x7f);
+            if b & 0x80 == 0 {
+                return Some((value, len));
+            }
+        }
+    }
+}
+
+/// A whole patch in memory.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+}
+
+impl Bytes for Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn skip(&mut self, len: u64) -> Option<()> {
+        let len = usize::try_from(len).ok()?;
+        let end = self.pos.checked_add(len)?;
+        if end > self.data.len() {
+            return None;
+        }
+        self.pos = end;
+        Some(())
+    }
+}
+
+/// Returns the total length of the target described by the window headers of `patch`.
+///
+/// `None` is returned if the headers cannot be parsed, for example when the patch is
+/// truncated or uses an encoding this reader does not understand.
+pub(crate) fn target_len(patch: &[u8]) -> Option<u64> {
+    read_target_len(&mut Reader::new(patch))
+}
+
+/// Like [`target_len`], for a patch read from `r`.
+pub(crate) fn read_target_len<B: Bytes>(r: &mut B) -> Option<u64> {
+    for &b in &VCDIFF_MAGIC {
+        if r.byte()? != b {
+            return None;
+        }
+    }
+
+    let hdr_ind = r.byte()?;
+    if hdr_ind & VCD_SECONDARY != 0 {
+        r.byte()?;
+    }
+    if hdr_ind & VCD_CODETABLE != 0 {
+        let (len, _) = r.varint()?;
+        r.skip(len)?;
+    }
+    if hdr_ind & VCD_APPHEADER != 0 {
+        let (len, _) = r.varint()?;
+        r.skip(len)?;
+    }
+
+    let mut total = 0u64;
+    while let Some(win_ind) = r.byte() {
+        if win_ind & (VCD_SOURCE | VCD_TARGET) != 0 {
+            r.varint()?; // copy window length
+            r.varint()?; // copy window offset
+        }
+
+        // The encoding length covers everything after itself, starting with the target length.
+        let (enclen, _) = r.varint()?;
+        let (tgtlen, tgtlen_size) = r.varint()?;
+        r.skip(enclen.checked_sub(tgtlen_size)?)?;
+        if tgtlen > MAX_TARGET_WINDOW {
+            return None;
+        }
+        total = total.checked_add(tgtlen)?;
+    }
+
+    Some(total)
+}
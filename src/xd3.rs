@@ -0,0 +1,213 @@
+//! Thin wrapper around `xd3_stream` shared by the in-memory and streaming APIs.
+
+use std::ops::{Deref, DerefMut};
+
+use super::binding;
+use super::{Error, Position, Result};
+use log::debug;
+
+pub(crate) const XD3_DEFAULT_WINSIZE: usize = 1 << 23;
+/// Largest window xdelta3 takes, for the input of the encoder and the target windows of
+/// the decoder (`XD3_HARDMAXWINSIZE` in xdelta3.h).
+pub(crate) const XD3_HARDMAXWINSIZE: usize = 1 << 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Encode,
+    Decode,
+}
+
+/// An `xd3_stream` that is freed on drop.
+///
+/// xdelta3 keeps pointers into the stream once it is configured, so it must not move.
+/// By default it is boxed; [`Xd3Stream::in_place`] uses memory owned by the caller.
+pub(crate) struct Xd3Stream<S = Box<binding::xd3_stream>>
+where
+    S: DerefMut<Target = binding::xd3_stream>,
+{
+    inner: S,
+}
+
+impl Xd3Stream {
+    pub(crate) fn new() -> Self {
+        let inner: binding::xd3_stream = unsafe { std::mem::zeroed() };
+        Self {
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl<'a> Xd3Stream<&'a mut binding::xd3_stream> {
+    /// Wraps `inner`, which must be zeroed, without allocating.
+    pub(crate) fn in_place(inner: &'a mut binding::xd3_stream) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: DerefMut<Target = binding::xd3_stream>> Xd3Stream<S> {
+    pub(crate) fn config(&mut self, cfg: &mut binding::xd3_config) -> Result<()> {
+        let ret = unsafe { binding::xd3_config_stream(&mut *self.inner, cfg) };
+        self.check(ret)
+    }
+
+    /// Attaches `src` to the stream. If `len` is known, xdelta3 is told the source size up front.
+    ///
+    /// # Safety
+    ///
+    /// `src` must stay at the same address and outlive every later call on this stream.
+    pub(crate) unsafe fn set_source(
+        &mut self,
+        src: &mut binding::xd3_source,
+        len: Option<u64>,
+    ) -> Result<()> {
+        let ret = match len {
+            Some(len) => binding::xd3_set_source_and_size(&mut *self.inner, src, len),
+            None => binding::xd3_set_source(&mut *self.inner, src),
+        };
+        self.check(ret)
+    }
+
+    /// Sets the application header written by the encoder (`xd3_set_appheader`).
+    ///
+    /// # Safety
+    ///
+    /// `header` must outlive every later call on this stream.
+    pub(crate) unsafe fn set_app_header(&mut self, header: &[u8]) {
+        self.inner.enc_appheader = header.as_ptr() as _;
+        self.inner.enc_appheadsz = header.len() as u32;
+    }
+
+    /// Returns the application header read by the decoder (`xd3_get_appheader`).
+    ///
+    /// The header is available once the decoder has returned `XD3_GOTHEADER`.
+    pub(crate) fn app_header(&self) -> Option<&[u8]> {
+        let state = self.inner.dec_state as u32;
+        if state < binding::xd3_decode_state::DEC_WININD as u32 {
+            return None;
+        }
+        if self.inner.dec_appheader.is_null() {
+            return None;
+        }
+        let len = self.inner.dec_appheadsz as usize;
+        Some(unsafe { std::slice::from_raw_parts(self.inner.dec_appheader, len) })
+    }
+
+    /// Hands `data` to xdelta3 as the next piece of input (`xd3_avail_input`).
+    ///
+    /// `data` must stay alive until xdelta3 asks for more input with `XD3_INPUT`.
+    pub(crate) fn avail_input(&mut self, data: &[u8]) {
+        self.inner.next_in = data.as_ptr();
+        self.inner.avail_in = data.len() as u32;
+    }
+
+    /// Returns the output produced by the last `XD3_OUTPUT` step.
+    pub(crate) fn output(&self) -> &[u8] {
+        if self.inner.avail_out == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.inner.next_out, self.inner.avail_out as usize) }
+    }
+
+    /// Marks the output returned by [`output`](Self::output) as consumed (`xd3_consume_output`).
+    pub(crate) fn consume_output(&mut self) {
+        self.inner.avail_out = 0;
+    }
+
+    /// Runs `xd3_encode_input` or `xd3_decode_input` once.
+    ///
+    /// Every return value that asks the caller to do something is passed through; failures
+    /// are turned into an [`Error`].
+    pub(crate) fn step(&mut self, mode: Mode) -> Result<binding::xd3_rvalues> {
+        let ret = unsafe {
+            match mode {
+                Mode::Encode => binding::xd3_encode_input(&mut *self.inner),
+                Mode::Decode => binding::xd3_decode_input(&mut *self.inner),
+            }
+        };
+
+        if !self.inner.msg.is_null() {
+            debug!("ret={:?}, msg={:?}", ret, unsafe {
+                std::ffi::CStr::from_ptr(self.inner.msg)
+            });
+        } else {
+            debug!("ret={:?}", ret);
+        }
+
+        use binding::xd3_rvalues::*;
+        for &rv in &[
+            XD3_INPUT,
+            XD3_OUTPUT,
+            XD3_GETSRCBLK,
+            XD3_GOTHEADER,
+            XD3_WINSTART,
+            XD3_WINFINISH,
+        ] {
+            if ret == rv as i32 {
+                return Ok(rv);
+            }
+        }
+        Err(self.error(ret))
+    }
+
+    /// Checks that the stream ended on a window boundary.
+    ///
+    /// When decoding, a patch that ends in the middle of a window is reported as
+    /// [`Error::InvalidInput`].
+    pub(crate) fn close(&mut self, mode: Mode) -> Result<()> {
+        let ret = unsafe { binding::xd3_close_stream(&mut *self.inner) };
+        match (mode, self.check(ret)) {
+            (Mode::Decode, Err(Error::Internal { msg, position, .. })) => {
+                Err(Error::InvalidInput { msg, position })
+            }
+            (_, r) => r,
+        }
+    }
+
+    pub(crate) fn position(&self) -> Position {
+        Position {
+            window: self.inner.current_window,
+            offset: self.inner.total_in,
+        }
+    }
+
+    pub(crate) fn error(&self, code: i32) -> Error {
+        Error::from_code(code, self.inner.msg, Some(self.position()))
+    }
+
+    fn check(&self, ret: i32) -> Result<()> {
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.error(ret))
+        }
+    }
+}
+
+impl<S: DerefMut<Target = binding::xd3_stream>> Deref for Xd3Stream<S> {
+    type Target = binding::xd3_stream;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<S: DerefMut<Target = binding::xd3_stream>> DerefMut for Xd3Stream<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<S: DerefMut<Target = binding::xd3_stream>> Drop for Xd3Stream<S> {
+    fn drop(&mut self) {
+        unsafe {
+            binding::xd3_free_stream(&mut *self.inner);
+        }
+    }
+}
+
+// SAFETY: the raw pointers in a boxed `xd3_stream` point into memory xdelta3 allocated for
+// the stream with `malloc`, or into buffers that belong to the owner of the stream, such
+// as the `SrcBuffer` and application header held next to it in `Codec`, which move to the
+// other thread with it. xdelta3 keeps no thread-local state, so the stream can be used and
+// freed on any thread. A stream `in_place` borrows its memory and is not `Send`.
+unsafe impl Send for Xd3Stream {}
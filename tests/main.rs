@@ -1,5 +1,7 @@
 #[cfg(test)]
 mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
     use std::fs::File;
     use std::io::Read;
     #[cfg(feature = "stream")]
@@ -20,13 +22,186 @@ mod tests {
         Some(out)
     }
 
+    /// Allocator that records the largest allocation made on each thread.
+    struct LargestAlloc;
+
+    thread_local! {
+        static LARGEST_ALLOC: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn record_alloc(size: usize) {
+        let _ = LARGEST_ALLOC.try_with(|largest| largest.set(largest.get().max(size)));
+    }
+
+    unsafe impl GlobalAlloc for LargestAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            record_alloc(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            record_alloc(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: LargestAlloc = LargestAlloc;
+
+    #[cfg(feature = "tokio")]
+    fn tokio_block_on<F: std::future::Future>(future: F) -> F::Output {
+        ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("failed to build runtime")
+            .block_on(future)
+    }
+
+    /// Reader that returns at most one byte per read, like a slow pipe.
+    struct OneByte<R>(R);
+
+    impl<R: Read> Read for OneByte<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    impl<R: std::io::Seek> std::io::Seek for OneByte<R> {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[cfg(feature = "stream")]
+    impl<R: futures::io::AsyncRead + Unpin> futures::io::AsyncRead for OneByte<R> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let len = buf.len().min(1);
+            std::pin::Pin::new(&mut self.0).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    /// Reader that fails once `len` bytes have been read.
+    struct FailAfter<'a>(&'a [u8]);
+
+    impl Read for FailAfter<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "source went away",
+                ));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    /// Source made of several pieces put together, which returns `Pending` before each
+    /// block if `pending` is set.
+    struct Pieces {
+        pieces: Vec<Vec<u8>>,
+        pending: bool,
+        woken: bool,
+    }
+
+    impl Source for Pieces {
+        fn total_len(&self) -> Option<u64> {
+            Some(self.pieces.iter().map(|piece| piece.len() as u64).sum())
+        }
+
+        fn poll_block(
+            &mut self,
+            cx: &mut std::task::Context<'_>,
+            blkno: u64,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.pending && !self.woken {
+                self.woken = true;
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            self.woken = false;
+            let mut pos = blkno as usize * buf.len();
+            let mut len = 0;
+            for piece in &self.pieces {
+                if pos >= piece.len() {
+                    pos -= piece.len();
+                    continue;
+                }
+                let part = &piece[pos..piece.len().min(pos + buf.len() - len)];
+                buf[len..len + part.len()].copy_from_slice(part);
+                len += part.len();
+                pos = 0;
+                if len == buf.len() {
+                    break;
+                }
+            }
+            std::task::Poll::Ready(Ok(len))
+        }
+    }
+
+    /// A source that never has a block ready, and records when it is dropped.
+    #[cfg(feature = "stream")]
+    struct Stalled {
+        len: u64,
+        dropped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    #[cfg(feature = "stream")]
+    impl Source for Stalled {
+        fn total_len(&self) -> Option<u64> {
+            Some(self.len)
+        }
+
+        fn poll_block(
+            &mut self,
+            _cx: &mut std::task::Context<'_>,
+            _blkno: u64,
+            _buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[cfg(feature = "stream")]
+    impl Drop for Stalled {
+        fn drop(&mut self) {
+            self.dropped
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(feature = "stream")]
+    fn assert_send<T: Send + 'static>(_: &T) {}
+
     fn check_decode(input: &[u8], src: &[u8]) -> Vec<u8> {
         let out_mem = decode(input, src).expect("Failed to decode");
+        let mut out_blocking = Vec::new();
+        sync::decode(input, src, &mut out_blocking).expect("Failed to decode");
+        assert_eq!(out_mem, out_blocking);
         #[cfg(feature = "stream")]
         {
             let out_async = decode2(input, src).expect("Failed to decode");
             assert_eq!(out_mem, out_async);
         }
+        #[cfg(feature = "tokio")]
+        {
+            let mut out_tokio = Vec::new();
+            tokio_block_on(xdelta3::stream::tokio::decode_async(
+                input,
+                src,
+                &mut out_tokio,
+            ))
+            .expect("Failed to decode");
+            assert_eq!(out_mem, out_tokio);
+        }
         out_mem
     }
 
@@ -38,6 +213,56 @@ mod tests {
         assert_eq!(&recode, &[1, 2, 3, 4, 5, 6, 7]);
     }
 
+    #[test]
+    fn large_expansion() {
+        // A long run encodes to a few bytes, far less than half of the target.
+        let input = vec![0u8; 1 << 20];
+        let source = [1, 2, 3];
+        let patch = encode(&input, &source).expect("failed to encode");
+        assert!(patch.len() * 2 < input.len());
+        assert_eq!(check_decode(&patch, &source), input);
+    }
+
+    #[test]
+    fn huge_target_claim() {
+        // Many windows whose headers each claim a 16 MiB target, the most a window may
+        // hold, adding up to terabytes.
+        let mut patch = vec![0xd6, 0xc3, 0xc4, 0x00, 0x00];
+        for _ in 0..100_000 {
+            patch.extend_from_slice(&[0x00, 0x04, 0x88, 0x80, 0x80, 0x00]);
+        }
+        assert_eq!(target_len(&patch), Some(100_000 << 24));
+
+        LARGEST_ALLOC.with(|largest| largest.set(0));
+        let result = decode(&patch, &[]);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+        // The output reserved up front is bounded by 64 bytes per byte of patch, not by the
+        // claim. xdelta3 allocates with malloc, so only the Rust side is counted here.
+        let largest = LARGEST_ALLOC.with(Cell::get);
+        assert!(largest <= patch.len() * 64, "allocated {} bytes", largest);
+    }
+
+    #[test]
+    fn invalid_patch() {
+        let result = decode(&[1, 2, 3, 4, 5], &[1, 2, 4, 4, 7, 6, 7]);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn truncated_patch() {
+        let source = [1, 2, 4, 4, 7, 6, 7];
+        let patch = encode(&[1, 2, 3, 4, 5, 6, 7], &source).expect("failed to encode");
+
+        let mut out = Vec::new();
+        let truncated = &patch[..patch.len() - 3];
+        let result = futures::executor::block_on(decode_async(truncated, &source[..], &mut out));
+        match result {
+            Err(Error::InvalidInput { position, .. }) => assert!(position.is_some()),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
     fn read_file(filename: &str) -> Vec<u8> {
         let mut file = File::open(filename).expect("Failed to open file");
         let mut data = Vec::new();
@@ -70,5 +295,1289 @@ mod tests {
 
         let patch_async = encode2(&input, &source).expect("failed to encode");
         assert_eq!(input, check_decode(&patch_async, &source));
+
+        #[cfg(feature = "tokio")]
+        {
+            let mut patch_tokio = Vec::new();
+            tokio_block_on(xdelta3::stream::tokio::encode_async(
+                &input[..],
+                &source[..],
+                &mut patch_tokio,
+            ))
+            .expect("failed to encode");
+            assert_eq!(patch_async, patch_tokio);
+
+            let mut compressed = Vec::new();
+            tokio_block_on(xdelta3::stream::tokio::compress_async(
+                &input[..],
+                &mut compressed,
+            ))
+            .expect("failed to compress");
+            let mut out = Vec::new();
+            tokio_block_on(xdelta3::stream::tokio::decompress_async(
+                &compressed[..],
+                &mut out,
+            ))
+            .expect("failed to decompress");
+            assert_eq!(input, out);
+        }
+    }
+
+    #[test]
+    fn blocking_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let mut patch = Vec::new();
+        sync::encode(&input[..], &source[..], &mut patch).expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let config = EncoderConfig::new()
+            .level(9)
+            .source_window_size(1 << 16)
+            .source_block_size(1 << 14);
+        let mut patch = Vec::new();
+        sync::encode_with(&input[..], &source[..], &mut patch, &config).expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let mut patch = Vec::new();
+        sync::compress(&input[..], &mut patch).expect("failed to compress");
+        let mut out = Vec::new();
+        sync::decompress(&patch[..], &mut out).expect("failed to decompress");
+        assert_eq!(input, out);
+    }
+
+    #[test]
+    fn seekable_source() {
+        // Pseudo-random data, so matches only come from the source.
+        let mut state = 7u32;
+        let source: Vec<u8> = (0..1 << 18)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        // The second half of the target copies from the start of the source, which a
+        // small source window has already dropped by then.
+        let half = source.len() / 2;
+        let input = [&source[half..], &source[..half]].concat();
+        let patch = encode(&input, &source).expect("failed to encode");
+
+        let config = DecoderConfig::new()
+            .source_window_size(1 << 16)
+            .source_block_size(1 << 14);
+        let result = sync::decode_with(&patch[..], &source[..], &mut Vec::new(), &config);
+        assert!(matches!(result, Err(Error::TooFarBack { .. })));
+
+        let mut out = Vec::new();
+        let src = std::io::Cursor::new(&source[..]);
+        sync::decode_seekable_with(&patch[..], src, &mut out, &config).expect("failed to decode");
+        assert_eq!(input, out);
+
+        #[cfg(feature = "stream")]
+        {
+            let result = futures::executor::block_on(decode_async_with(
+                &patch[..],
+                &source[..],
+                &mut Vec::new(),
+                &config,
+            ));
+            assert!(matches!(result, Err(Error::TooFarBack { .. })));
+
+            let mut out = Vec::new();
+            let src = futures::io::Cursor::new(&source[..]);
+            futures::executor::block_on(decode_async_seekable_with(
+                &patch[..],
+                src,
+                &mut out,
+                &config,
+            ))
+            .expect("failed to decode");
+            assert_eq!(input, out);
+        }
+
+        #[cfg(feature = "tokio")]
+        {
+            let mut out = Vec::new();
+            let src = std::io::Cursor::new(&source[..]);
+            tokio_block_on(xdelta3::stream::tokio::decode_async_seekable_with(
+                &patch[..],
+                src,
+                &mut out,
+                &config,
+            ))
+            .expect("failed to decode");
+            assert_eq!(input, out);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn mmap_source() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source_path = format!("{}/{}", fixure_path, "file_v1.bin");
+        let source = read_file(&source_path);
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+        let map = MmapSource::open(&source_path).expect("failed to map source");
+        assert_eq!(map.len(), source.len() as u64);
+
+        let mut patch = Vec::new();
+        sync::encode_mmap(&input[..], &map, &mut patch).expect("failed to encode");
+        let mut out = Vec::new();
+        sync::decode_mmap(&patch[..], &map, &mut out).expect("failed to decode");
+        assert_eq!(input, out);
+
+        #[cfg(feature = "stream")]
+        {
+            let mut patch = Vec::new();
+            futures::executor::block_on(encode_async_mmap(&input[..], &map, &mut patch))
+                .expect("failed to encode");
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_async_mmap(&patch[..], &map, &mut out))
+                .expect("failed to decode");
+            assert_eq!(input, out);
+        }
+
+        // A source file that shrinks while mapped is reported instead of read.
+        let path = std::env::temp_dir().join(format!("xdelta3-mmap-{}.bin", std::process::id()));
+        std::fs::write(&path, &source).expect("failed to write source");
+        let map = MmapSource::open(&path).expect("failed to map source");
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(10))
+            .expect("failed to truncate source");
+        let result = sync::decode_mmap(&patch[..], &map, &mut Vec::new());
+        drop(map);
+        std::fs::remove_file(&path).expect("failed to remove source");
+        assert!(matches!(result, Err(Error::Source(_))));
+    }
+
+    #[test]
+    fn reader_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let mut reader =
+            sync::EncoderReader::new(&input[..], &source[..]).expect("failed to create reader");
+        let mut patch = Vec::new();
+        // Small reads, so output is handed out across several calls.
+        let mut chunk = [0u8; 100];
+        loop {
+            let len = reader.read(&mut chunk).expect("failed to encode");
+            if len == 0 {
+                break;
+            }
+            patch.extend_from_slice(&chunk[..len]);
+        }
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let mut reader =
+            sync::DecoderReader::new(&patch[..], &source[..]).expect("failed to create reader");
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).expect("failed to decode");
+        assert_eq!(input, out);
+
+        let mut reader = sync::DecoderReader::new(&patch[..patch.len() - 3], &source[..])
+            .expect("failed to create reader");
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        #[cfg(feature = "stream")]
+        {
+            use futures::io::AsyncReadExt;
+
+            let mut reader =
+                EncoderReader::new(&input[..], &source[..]).expect("failed to create reader");
+            let mut patch_async = Vec::new();
+            futures::executor::block_on(reader.read_to_end(&mut patch_async))
+                .expect("failed to encode");
+            assert_eq!(patch, patch_async);
+
+            let mut reader =
+                DecoderReader::new(&patch[..], &source[..]).expect("failed to create reader");
+            let mut out = Vec::new();
+            futures::executor::block_on(reader.read_to_end(&mut out)).expect("failed to decode");
+            assert_eq!(input, out);
+        }
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        use std::io::Write;
+
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        // A window smaller than the input, so it is handed to xdelta3 in several pieces.
+        let config = EncoderConfig::new().window_size(1 << 16);
+        let mut writer = sync::EncoderWriter::with_config(&source[..], Vec::new(), &config)
+            .expect("failed to create writer");
+        for chunk in input.chunks(1000) {
+            writer.write_all(chunk).expect("failed to encode");
+        }
+        let patch = writer.finish().expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let mut writer =
+            sync::DecoderWriter::new(&source[..], Vec::new()).expect("failed to create writer");
+        for chunk in patch.chunks(1000) {
+            writer.write_all(chunk).expect("failed to decode");
+        }
+        assert_eq!(input, writer.finish().expect("failed to decode"));
+
+        let mut writer =
+            sync::DecoderWriter::new(&source[..], Vec::new()).expect("failed to create writer");
+        writer
+            .write_all(&patch[..patch.len() - 3])
+            .expect("failed to decode");
+        let result = writer.finish();
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+
+        #[cfg(feature = "stream")]
+        {
+            use futures::io::AsyncWriteExt;
+
+            let mut out = Vec::new();
+            let mut writer =
+                DecoderWriter::new(&source[..], &mut out).expect("failed to create writer");
+            futures::executor::block_on(async {
+                for chunk in patch.chunks(1000) {
+                    writer.write_all(chunk).await?;
+                }
+                writer.close().await
+            })
+            .expect("failed to decode");
+            drop(writer);
+            assert_eq!(input, out);
+
+            let mut patch_async = Vec::new();
+            let mut writer = EncoderWriter::with_config(&source[..], &mut patch_async, &config)
+                .expect("failed to create writer");
+            futures::executor::block_on(async {
+                writer.write_all(&input).await?;
+                writer.close().await
+            })
+            .expect("failed to encode");
+            drop(writer);
+            assert_eq!(patch, patch_async);
+        }
+    }
+
+    #[test]
+    fn sans_io_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+        let block = |blkno: u64, block_size: usize| {
+            let start = (blkno as usize * block_size).min(source.len());
+            let end = source.len().min(start + block_size);
+            &source[start..end]
+        };
+
+        let config = EncoderConfig::new().window_size(1 << 16);
+        let mut encoder = Encoder::with_source(&config, None).expect("failed to create encoder");
+        let mut chunks = input.chunks(1000);
+        let mut patch = Vec::new();
+        let mut windows = 0;
+        loop {
+            match encoder.step().expect("failed to encode") {
+                Event::NeedInput => match chunks.next() {
+                    Some(chunk) => encoder.provide_input(chunk),
+                    None => encoder.finish_input(),
+                },
+                Event::Output(data) => patch.extend_from_slice(data),
+                Event::NeedSourceBlock(blkno) => {
+                    let data = block(blkno, encoder.block_size());
+                    encoder.provide_source_block(blkno, data);
+                }
+                Event::WindowFinish => windows += 1,
+                Event::Done => break,
+                _ => {}
+            }
+        }
+        assert!(windows > 1);
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let mut decoder = Decoder::with_source(&DecoderConfig::new(), Some(source.len() as u64))
+            .expect("failed to create decoder");
+        let mut chunks = patch.chunks(1000);
+        let mut out = Vec::new();
+        let mut got_header = false;
+        loop {
+            match decoder.step().expect("failed to decode") {
+                Event::NeedInput => match chunks.next() {
+                    Some(chunk) => decoder.provide_input(chunk),
+                    None => decoder.finish_input(),
+                },
+                Event::Output(data) => out.extend_from_slice(data),
+                Event::NeedSourceBlock(blkno) => {
+                    let data = block(blkno, decoder.block_size());
+                    decoder.provide_source_block(blkno, data);
+                }
+                Event::GotHeader => got_header = true,
+                Event::Done => break,
+                _ => {}
+            }
+        }
+        assert!(got_header);
+        assert_eq!(input, out);
+    }
+
+    #[test]
+    fn sans_io_empty_input() {
+        let source = [1, 2, 4, 4, 7, 6, 7];
+        let input = [1, 2, 3, 4, 5, 6, 7];
+        let mut encoder = Encoder::with_source(&EncoderConfig::new(), Some(source.len() as u64))
+            .expect("failed to create encoder");
+        let mut pieces = vec![&[][..], &input[..3], &[][..], &input[3..]].into_iter();
+        let mut patch = Vec::new();
+        loop {
+            match encoder.step().expect("failed to encode") {
+                Event::NeedInput => match pieces.next() {
+                    Some(piece) => encoder.provide_input(piece),
+                    None => encoder.finish_input(),
+                },
+                Event::Output(data) => patch.extend_from_slice(data),
+                Event::NeedSourceBlock(blkno) => {
+                    assert_eq!(blkno, 0);
+                    encoder.provide_source_block(blkno, &source);
+                }
+                Event::Done => break,
+                _ => {}
+            }
+        }
+        assert!(pieces.next().is_none());
+        assert_eq!(decode(&patch, &source).expect("failed to decode"), input);
+    }
+
+    #[test]
+    fn sans_io_out_of_order_blocks() {
+        let source: Vec<u8> = (0..200_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        // The second half of the source first, so blocks are asked for out of order.
+        let mut input = source[100_000..].to_vec();
+        input.extend_from_slice(&source[..100_000]);
+        let block = |blkno: u64, block_size: usize| {
+            let start = (blkno as usize * block_size).min(source.len());
+            let end = source.len().min(start + block_size);
+            &source[start..end]
+        };
+
+        // Blocks that were not asked for are ignored, so the garbage never gets in.
+        let garbage = vec![0xffu8; 1 << 14];
+        let config = EncoderConfig::new().source_block_size(1 << 14);
+        let mut encoder = Encoder::with_source(&config, Some(source.len() as u64))
+            .expect("failed to create encoder");
+        let mut chunks = input.chunks(10_000);
+        let mut patch = Vec::new();
+        loop {
+            match encoder.step().expect("failed to encode") {
+                Event::NeedInput => match chunks.next() {
+                    Some(chunk) => encoder.provide_input(chunk),
+                    None => encoder.finish_input(),
+                },
+                Event::Output(data) => patch.extend_from_slice(data),
+                Event::NeedSourceBlock(blkno) => {
+                    encoder.provide_source_block(blkno + 1, &garbage);
+                    encoder.provide_source_block(0, &garbage);
+                    encoder.provide_source_block(blkno, block(blkno, encoder.block_size()));
+                    encoder.provide_source_block(blkno, &garbage);
+                }
+                Event::Done => break,
+                _ => {}
+            }
+        }
+        assert!(patch.len() < input.len() / 4);
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let config = DecoderConfig::new().source_block_size(1 << 14);
+        let mut decoder = Decoder::with_source(&config, Some(source.len() as u64))
+            .expect("failed to create decoder");
+        let mut chunks = patch.chunks(1000);
+        let mut out = Vec::new();
+        loop {
+            match decoder.step().expect("failed to decode") {
+                Event::NeedInput => match chunks.next() {
+                    Some(chunk) => decoder.provide_input(chunk),
+                    None => decoder.finish_input(),
+                },
+                Event::Output(data) => out.extend_from_slice(data),
+                Event::NeedSourceBlock(blkno) => {
+                    decoder.provide_source_block(blkno + 1, &garbage);
+                    decoder.provide_source_block(0, &garbage);
+                    decoder.provide_source_block(blkno, block(blkno, decoder.block_size()));
+                    decoder.provide_source_block(blkno, &garbage);
+                }
+                Event::Done => break,
+                _ => {}
+            }
+        }
+        assert_eq!(input, out);
+    }
+
+    #[test]
+    #[should_panic(expected = "more than the window size")]
+    fn sans_io_oversized_input() {
+        let config = EncoderConfig::new().window_size(1 << 16);
+        let mut encoder = Encoder::new(&config).expect("failed to create encoder");
+        assert_eq!(encoder.input_size(), 1 << 16);
+        assert_eq!(encoder.step().expect("failed to encode"), Event::NeedInput);
+        encoder.provide_input(&vec![0u8; (1 << 16) + 1]);
+    }
+
+    #[test]
+    fn progress_reports() {
+        use std::sync::{Arc, Mutex};
+
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&reports);
+        let config = EncoderConfig::new()
+            .window_size(1 << 16)
+            .progress(move |progress| recorder.lock().unwrap().push(*progress));
+        let mut patch = Vec::new();
+        sync::encode_with(&input[..], &source[..], &mut patch, &config).expect("failed to encode");
+        let encoded = std::mem::take(&mut *reports.lock().unwrap());
+        let last = encoded.last().expect("no progress reported");
+        assert!(last.window > 0);
+        assert_eq!(last.input_bytes, input.len() as u64);
+        assert_eq!(last.output_bytes, patch.len() as u64);
+        assert!(last.source_bytes > 0);
+        assert!(encoded.iter().all(|p| p.target_window_len.is_none()));
+        assert!(encoded.iter().all(|p| p.total_target_len.is_none()));
+        assert!(encoded
+            .windows(2)
+            .all(|w| w[0].input_bytes <= w[1].input_bytes && w[0].window <= w[1].window));
+
+        // The total comes from the headers of a seekable patch.
+        let mut seekable = std::io::Cursor::new(&patch[..]);
+        let total = sync::target_len(&mut seekable).expect("failed to read the headers");
+        assert_eq!(total, Some(input.len() as u64));
+        assert_eq!(target_len(&patch), total);
+        let recorder = Arc::clone(&reports);
+        let config = DecoderConfig::new()
+            .total_target_len(total.unwrap())
+            .progress(move |progress| recorder.lock().unwrap().push(*progress));
+        let mut out = Vec::new();
+        sync::decode_with(seekable, &source[..], &mut out, &config).expect("failed to decode");
+        assert_eq!(out, input);
+        let decoded = std::mem::take(&mut *reports.lock().unwrap());
+        assert_eq!(decoded.last().unwrap().output_bytes, input.len() as u64);
+        let window_lens: u64 = decoded.iter().filter_map(|p| p.target_window_len).sum();
+        assert_eq!(window_lens, input.len() as u64);
+        assert!(decoded.iter().all(|p| p.total_target_len == total));
+
+        // The in-memory functions report too.
+        let recorder = Arc::clone(&reports);
+        let config =
+            DecoderConfig::new().progress(move |progress| recorder.lock().unwrap().push(*progress));
+        decode_with(&patch, &source, &config).expect("failed to decode");
+        let in_memory = reports.lock().unwrap();
+        assert_eq!(in_memory.len(), decoded.len());
+        assert_eq!(in_memory.last().unwrap().output_bytes, input.len() as u64);
+        assert!(in_memory.iter().all(|p| p.total_target_len == total));
+    }
+
+    #[test]
+    fn cancel_between_windows() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let config = EncoderConfig::new().window_size(1 << 16);
+        let patch = encode_with(&input, &source, &config).expect("failed to encode");
+
+        // Cancel from the observer as the first window finishes.
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let config = DecoderConfig::new()
+            .cancel_token(token.clone())
+            .progress(move |progress| {
+                if progress.target_window_len.is_some() {
+                    canceller.cancel();
+                }
+            });
+        let mut out = Vec::new();
+        let result = sync::decode_with(&patch[..], &source[..], &mut out, &config);
+        match result {
+            Err(Error::Cancelled { windows, position }) => {
+                assert_eq!(windows, 1);
+                assert!(position.is_some());
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(out, &input[..1 << 16]);
+
+        // The complete window is flushed through a buffered writer too.
+        let buffered_token = CancelToken::new();
+        let canceller = buffered_token.clone();
+        let config = DecoderConfig::new()
+            .cancel_token(buffered_token)
+            .progress(move |progress| {
+                if progress.target_window_len.is_some() {
+                    canceller.cancel();
+                }
+            });
+        let mut writer = std::io::BufWriter::with_capacity(1 << 20, Vec::new());
+        let result = sync::decode_with(&patch[..], &source[..], &mut writer, &config);
+        assert!(matches!(result, Err(Error::Cancelled { windows: 1, .. })));
+        assert_eq!(writer.get_ref(), &input[..1 << 16]);
+
+        // A token cancelled up front stops before any input is read.
+        let config = EncoderConfig::new().cancel_token(token);
+        let result = sync::encode_with(&input[..], &source[..], &mut Vec::new(), &config);
+        assert!(matches!(result, Err(Error::Cancelled { windows: 0, .. })));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn cancel_async_buffered() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let config = EncoderConfig::new().window_size(1 << 16);
+        let patch = encode_with(&input, &source, &config).expect("failed to encode");
+
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let config = DecoderConfig::new()
+            .cancel_token(token)
+            .progress(move |progress| {
+                if progress.target_window_len.is_some() {
+                    canceller.cancel();
+                }
+            });
+        let mut writer = futures::io::BufWriter::with_capacity(1 << 20, Vec::new());
+        let result = futures::executor::block_on(decode_async_with(
+            &patch[..],
+            &source[..],
+            &mut writer,
+            &config,
+        ));
+        assert!(matches!(result, Err(Error::Cancelled { windows: 1, .. })));
+        assert_eq!(writer.get_ref(), &input[..1 << 16]);
+    }
+
+    #[test]
+    fn into_buffer_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+        let patch = encode(&input, &source).expect("failed to encode");
+
+        let mut buf = vec![0u8; input.len() * 2];
+        let len = encode_into(&input, &source, &mut buf).expect("failed to encode");
+        assert_eq!(&buf[..len], &patch[..]);
+        let result = encode_into(&input, &source, &mut buf[..len - 1]);
+        match result {
+            Err(Error::OutputTooSmall { required }) => assert_eq!(required, Some(len as u64)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The same buffer is reused for the result.
+        let len = decode_into(&patch, &source, &mut buf).expect("failed to decode");
+        assert_eq!(&buf[..len], &input[..]);
+        let result = decode_into(&patch, &source, &mut buf[..input.len() - 1]);
+        match result {
+            Err(Error::OutputTooSmall { required }) => {
+                assert_eq!(required, Some(input.len() as u64))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn short_source_reads() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+        let patch = encode(&input, &source).expect("failed to encode");
+
+        let mut patch_blocking = Vec::new();
+        sync::encode(&input[..], OneByte(&source[..]), &mut patch_blocking)
+            .expect("failed to encode");
+        assert_eq!(patch, patch_blocking);
+
+        let mut out = Vec::new();
+        sync::decode(&patch[..], OneByte(&source[..]), &mut out).expect("failed to decode");
+        assert_eq!(input, out);
+
+        let mut out = Vec::new();
+        let source_file = std::io::Cursor::new(&source[..]);
+        sync::decode_seekable(&patch[..], OneByte(source_file), &mut out)
+            .expect("failed to decode");
+        assert_eq!(input, out);
+
+        #[cfg(feature = "stream")]
+        {
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_async(&patch[..], OneByte(&source[..]), &mut out))
+                .expect("failed to decode");
+            assert_eq!(input, out);
+        }
+
+        // A source that fails part way through is reported, not taken for its end.
+        let result = sync::decode(
+            &patch[..],
+            FailAfter(&source[..source.len() / 2]),
+            &mut Vec::new(),
+        );
+        match result {
+            Err(Error::Source(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn source_trait() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source_path = format!("{}/{}", fixure_path, "file_v1.bin");
+        let source = read_file(&source_path);
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+        let patch = encode(&input, &source).expect("failed to encode");
+        let pieces = |pending| Pieces {
+            pieces: source.chunks(5000).map(<[u8]>::to_vec).collect(),
+            pending,
+            woken: false,
+        };
+        // Small blocks, so the source is read in many of them.
+        let config = DecoderConfig::new().source_block_size(1 << 14);
+
+        let mut out = Vec::new();
+        sync::decode_source_with(&patch[..], pieces(false), &mut out, &config)
+            .expect("failed to decode");
+        assert_eq!(input, out);
+
+        let mut out = Vec::new();
+        sync::decode_source(&patch[..], source.clone(), &mut out).expect("failed to decode");
+        assert_eq!(input, out);
+
+        let file = File::open(&source_path).expect("failed to open source");
+        let mut out = Vec::new();
+        sync::decode_source_with(&patch[..], file, &mut out, &config).expect("failed to decode");
+        assert_eq!(input, out);
+
+        let mut patch_blocking = Vec::new();
+        sync::encode_source(&input[..], &source[..], &mut patch_blocking)
+            .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch_blocking, &source));
+
+        // The blocking functions cannot wait for a source.
+        let result = sync::decode_source(&patch[..], pieces(true), &mut Vec::new());
+        match result {
+            Err(Error::Source(e)) => assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let result = sync::encode_source(&input[..], pieces(true), &mut Vec::new());
+        match result {
+            Err(Error::Source(e)) => assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        #[cfg(feature = "stream")]
+        {
+            let mut out = Vec::new();
+            futures::executor::block_on(decode_async_source_with(
+                &patch[..],
+                pieces(true),
+                &mut out,
+                &config,
+            ))
+            .expect("failed to decode");
+            assert_eq!(input, out);
+
+            let mut patch_async = Vec::new();
+            futures::executor::block_on(encode_async_source(
+                &input[..],
+                pieces(true),
+                &mut patch_async,
+            ))
+            .expect("failed to encode");
+            assert_eq!(input, check_decode(&patch_async, &source));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipe_source() {
+        use std::io::Write;
+        use std::os::unix::io::OwnedFd;
+        use std::process::{Command, Stdio};
+
+        let source: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut input = source.clone();
+        input[20_000..20_100].iter_mut().for_each(|b| *b = 0);
+        let patch = encode(&input, &source).expect("failed to encode");
+
+        // A pipe has no length and cannot seek, so it is read front to back.
+        let mut cat = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to run cat");
+        let mut stdin = cat.stdin.take().unwrap();
+        let fed = source.clone();
+        let feeder = std::thread::spawn(move || stdin.write_all(&fed));
+        let pipe = File::from(OwnedFd::from(cat.stdout.take().unwrap()));
+        assert_eq!(pipe.total_len(), None);
+        assert!(!pipe.can_seek());
+
+        let mut out = Vec::new();
+        sync::decode_source(&patch[..], pipe, &mut out).expect("failed to decode");
+        assert_eq!(input, out);
+        feeder.join().unwrap().expect("failed to feed the pipe");
+        cat.wait().expect("failed to wait for cat");
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn send_futures() {
+        use futures::io::Cursor;
+
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let config = EncoderConfig::new().window_size(1 << 16);
+        let src = source.clone();
+        let data = input.clone();
+        let encode = async move {
+            let mut patch = Vec::new();
+            encode_async_source_with(Cursor::new(data), src, &mut patch, &config)
+                .await
+                .map(|_| patch)
+        };
+        assert_send(&encode);
+        let patch = std::thread::spawn(move || futures::executor::block_on(encode))
+            .join()
+            .expect("encoder thread panicked")
+            .expect("failed to encode");
+
+        let src = source.clone();
+        let decode = async move {
+            let mut out = Vec::new();
+            decode_async(Cursor::new(patch), Cursor::new(src), &mut out)
+                .await
+                .map(|_| out)
+        };
+        assert_send(&decode);
+        let out = std::thread::spawn(move || futures::executor::block_on(decode))
+            .join()
+            .expect("decoder thread panicked")
+            .expect("failed to decode");
+        assert_eq!(input, out);
+
+        let config = DecoderConfig::new();
+        assert_send(&decode_async_seekable_with(
+            Cursor::new(Vec::new()),
+            Cursor::new(Vec::new()),
+            Vec::new(),
+            &config,
+        ));
+        assert_send(&compress_async(Cursor::new(Vec::new()), Vec::new()));
+        assert_send(&Encoder::new(&EncoderConfig::new()).expect("failed to create encoder"));
+        assert_send(&Decoder::new(&config).expect("failed to create decoder"));
+        assert_send(
+            &stream::EncoderReader::new(Cursor::new(input), Cursor::new(source))
+                .expect("failed to create reader"),
+        );
+        assert_send(
+            &sync::DecoderWriter::new(std::io::empty(), Vec::new())
+                .expect("failed to create writer"),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn tokio_offload() {
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let mut state = 1u32;
+        let source: Vec<u8> = (0..1 << 24)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let mut input = source.clone();
+        for i in (0..input.len()).step_by(4096) {
+            input[i] ^= 0xff;
+        }
+
+        // The input and source never keep the encoder waiting, so on a current_thread
+        // runtime the ticker only runs while a step is on the blocking pool. Run in place,
+        // each window would stall it for as long as the window takes to encode.
+        let gap = Arc::new(Mutex::new(Duration::default()));
+        let longest = gap.clone();
+        let config = EncoderConfig::new().window_size(1 << 22);
+        let (patch, stats) = tokio_block_on(async {
+            let ticker = ::tokio::spawn(async move {
+                let mut last = Instant::now();
+                loop {
+                    ::tokio::task::yield_now().await;
+                    let now = Instant::now();
+                    let mut longest = longest.lock().unwrap();
+                    *longest = (*longest).max(now - last);
+                    last = now;
+                }
+            });
+            let mut patch = Vec::new();
+            let result = xdelta3::stream::tokio::encode_async_with(
+                &input[..],
+                &source[..],
+                &mut patch,
+                &config,
+            )
+            .await;
+            ticker.abort();
+            result.map(|stats| (patch, stats))
+        })
+        .expect("failed to encode");
+        assert!(stats.windows >= 4);
+        assert_eq!(input, check_decode(&patch, &source));
+
+        // Only the reads and writes between steps hold the ticker up, for far less than
+        // a window takes to encode.
+        let gap = *gap.lock().unwrap();
+        let window_time = stats.codec_time / stats.windows as u32;
+        assert!(
+            gap < Duration::from_millis(50).min(window_time / 2),
+            "ticker stalled for {:?}, a window takes {:?}",
+            gap,
+            window_time
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn drop_mid_window() {
+        use std::future::Future;
+
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+        let patch = encode(&input, &source).expect("failed to encode");
+
+        let dropped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let src = Stalled {
+            len: source.len() as u64,
+            dropped: dropped.clone(),
+        };
+        let mut out = Vec::new();
+        let mut decode = Box::pin(decode_async_source(&patch[..], src, &mut out));
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+        // The patch is read until the first window asks for a source block, which never
+        // comes, so the stream is left in the middle of the window.
+        assert!(decode.as_mut().poll(&mut cx).is_pending());
+        assert!(!dropped.load(std::sync::atomic::Ordering::SeqCst));
+        drop(decode);
+        assert!(dropped.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn instruction_counts() {
+        let mut state = 1u32;
+        let mut noise = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (state >> 16) as u8
+                })
+                .collect()
+        };
+        let source = noise(50_000);
+        let mut input = source[..20_000].to_vec();
+        input.extend_from_slice(&[7u8; 5000]);
+        input.extend_from_slice(&noise(3000));
+        input.extend_from_slice(&source[20_000..]);
+
+        // build.rs sets XD3_DEBUG to 0, so this is the release build of libxdelta3, and
+        // the counters are kept there too.
+        let stats =
+            sync::encode(&input[..], &source[..], &mut Vec::new()).expect("failed to encode");
+        let instructions = stats.instructions.expect("encoder counts instructions");
+        assert!(instructions.add_bytes >= 3000);
+        assert!(instructions.run_bytes >= 4000);
+        assert!(instructions.copy_bytes_from_source >= 45_000);
+        assert_eq!(
+            instructions.add_bytes + instructions.copy_bytes + instructions.run_bytes,
+            input.len() as u64
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let config = EncoderConfig::new().window_size(1 << 16);
+        let mut patch = Vec::new();
+        let encoded = sync::encode_with(&input[..], &source[..], &mut patch, &config)
+            .expect("failed to encode");
+        assert_eq!(encoded.input_bytes, input.len() as u64);
+        assert_eq!(encoded.output_bytes, patch.len() as u64);
+        assert!(encoded.source_bytes > 0);
+        assert!(encoded.windows > 1);
+        let instructions = encoded.instructions.expect("encoder counts instructions");
+        assert_eq!(
+            instructions.add_bytes + instructions.copy_bytes + instructions.run_bytes,
+            input.len() as u64
+        );
+        assert!(instructions.copy_bytes_from_source > 0);
+        assert!(instructions.copy_bytes_from_source <= instructions.copy_bytes);
+
+        let mut out = Vec::new();
+        let decoded = sync::decode(&patch[..], &source[..], &mut out).expect("failed to decode");
+        assert_eq!(decoded.input_bytes, patch.len() as u64);
+        assert_eq!(decoded.output_bytes, input.len() as u64);
+        assert_eq!(decoded.windows, encoded.windows);
+        assert_eq!(decoded.instructions, None);
+
+        #[cfg(feature = "stream")]
+        {
+            let mut out = Vec::new();
+            let decoded_async =
+                futures::executor::block_on(decode_async(&patch[..], &source[..], &mut out))
+                    .expect("failed to decode");
+            assert_eq!(decoded_async.input_bytes, decoded.input_bytes);
+            assert_eq!(decoded_async.source_bytes, decoded.source_bytes);
+            assert_eq!(decoded_async.output_bytes, decoded.output_bytes);
+            assert_eq!(decoded_async.windows, decoded.windows);
+        }
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let configs = [
+            EncoderConfig::new().level(0),
+            EncoderConfig::new().level(1),
+            EncoderConfig::new().level(9),
+            EncoderConfig::new().no_compress(true),
+            EncoderConfig::new()
+                .matcher(Matcher::Soft)
+                .window_size(1 << 16)
+                .iopt_size(0)
+                .sprevsz(1 << 12),
+        ];
+        for config in &configs {
+            let patch = encode_with(&input, &source, config).expect("failed to encode");
+            assert_eq!(input, check_decode(&patch, &source));
+
+            #[cfg(feature = "stream")]
+            {
+                let mut patch = Vec::new();
+                futures::executor::block_on(encode_async_with(
+                    &input[..],
+                    &source[..],
+                    &mut patch,
+                    config,
+                ))
+                .expect("failed to encode");
+                assert_eq!(input, check_decode(&patch, &source));
+            }
+        }
+
+        let config = DecoderConfig::new().window_size(1 << 14);
+        let patch = encode(&input, &source).expect("failed to encode");
+        assert_eq!(
+            input,
+            decode_with(&patch, &source, &config).expect("failed to decode")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "stream")]
+    fn source_window_round_trip() {
+        // Pseudo-random data, so matches only come from the source.
+        let mut state = 1u32;
+        let source: Vec<u8> = (0..1 << 18)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let mut input = source.clone();
+        for i in (0..input.len()).step_by(10_000) {
+            input[i] = input[i].wrapping_add(1);
+        }
+
+        let config = EncoderConfig::new()
+            .source_window_size(1 << 16)
+            .source_block_size(1 << 14);
+        let mut patch = Vec::new();
+        futures::executor::block_on(encode_async_with(
+            &input[..],
+            &source[..],
+            &mut patch,
+            &config,
+        ))
+        .expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let config = DecoderConfig::new().source_block_size(1 << 14);
+        let mut out = Vec::new();
+        futures::executor::block_on(decode_async_with(
+            &patch[..],
+            &source[..],
+            &mut out,
+            &config,
+        ))
+        .expect("failed to decode");
+        assert_eq!(input, out);
+
+        let config = EncoderConfig::new().source_block_size(1000);
+        let result = futures::executor::block_on(encode_async_with(
+            &input[..],
+            &source[..],
+            &mut Vec::new(),
+            &config,
+        ));
+        assert!(matches!(result, Err(Error::InvalidConfig { .. })));
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let patch = compress(&input).expect("failed to compress");
+        assert_eq!(input, decompress(&patch).expect("failed to decompress"));
+        assert_eq!(input, check_decode(&patch, &[]));
+
+        #[cfg(feature = "stream")]
+        {
+            let mut patch_async = Vec::new();
+            futures::executor::block_on(compress_async(&input[..], &mut patch_async))
+                .expect("failed to compress");
+            let mut out = Vec::new();
+            futures::executor::block_on(decompress_async(&patch_async[..], &mut out))
+                .expect("failed to decompress");
+            assert_eq!(input, out);
+        }
+
+        // A patch that copies from its source cannot be decompressed.
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let patch = encode(&input, &source).expect("failed to encode");
+        let result = decompress(&patch);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn secondary_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        assert!(SecondaryCompression::Djw.is_available());
+        assert!(SecondaryCompression::Fgk.is_available());
+        assert_eq!(
+            SecondaryCompression::Lzma.is_available(),
+            cfg!(feature = "lzma")
+        );
+
+        let sections = SecondarySections {
+            addresses: false,
+            ..SecondarySections::default()
+        };
+        for &secondary in SecondaryCompression::available() {
+            for &sections in &[SecondarySections::default(), sections] {
+                let config = EncoderConfig::new()
+                    .secondary(secondary)
+                    .secondary_sections(sections);
+                let patch = encode_with(&input, &source, &config).expect("failed to encode");
+                assert_eq!(input, check_decode(&patch, &source));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "lzma"))]
+    fn secondary_unavailable() {
+        let config = EncoderConfig::new().secondary(SecondaryCompression::Lzma);
+        let result = encode_with(&[1, 2, 3], &[1, 2, 3], &config);
+        assert!(matches!(result, Err(Error::NoSecondary { .. })));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let config = EncoderConfig::new().checksum(true);
+        let patch = encode_with(&input, &source, &config).expect("failed to encode");
+        assert_eq!(input, check_decode(&patch, &source));
+
+        let wrong_source: Vec<u8> = source.iter().map(|b| b ^ 0x55).collect();
+        match decode(&patch, &wrong_source) {
+            Err(Error::ChecksumMismatch { position, .. }) => assert!(position.is_some()),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        #[cfg(feature = "stream")]
+        {
+            let mut out = Vec::new();
+            let result =
+                futures::executor::block_on(decode_async(&patch[..], &wrong_source[..], &mut out));
+            assert!(matches!(result, Err(Error::ChecksumMismatch { .. })));
+        }
+
+        let config = DecoderConfig::new().verify_checksum(false);
+        let output = decode_with(&patch, &wrong_source, &config).expect("failed to decode");
+        assert_ne!(input, output);
+    }
+
+    #[test]
+    fn app_header_round_trip() {
+        let source = [1, 2, 4, 4, 7, 6, 7];
+        let input = [1, 2, 3, 4, 5, 6, 7];
+
+        let patch = encode(&input, &source).expect("failed to encode");
+        assert_eq!(app_header(&patch).expect("failed to read header"), None);
+
+        let header = CliAppHeader {
+            target_name: "file_v2.bin".to_owned(),
+            source_name: "file_v1.bin".to_owned(),
+            ..CliAppHeader::default()
+        };
+        let config = EncoderConfig::new().app_header(header.to_bytes().unwrap());
+        let patch = encode_with(&input, &source, &config).expect("failed to encode");
+        assert_eq!(check_decode(&patch, &source), input);
+
+        let read = app_header(&patch).expect("failed to read header");
+        assert_eq!(read.as_deref(), Some(&b"file_v2.bin//file_v1.bin/"[..]));
+        assert_eq!(CliAppHeader::parse(&read.unwrap()), Some(header));
+        #[cfg(feature = "stream")]
+        {
+            let read = futures::executor::block_on(app_header_async(&patch[..]))
+                .expect("failed to read header");
+            assert_eq!(read.as_deref(), Some(&b"file_v2.bin//file_v1.bin/"[..]));
+        }
+
+        assert!(matches!(
+            app_header(&patch[..3]),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert_eq!(CliAppHeader::parse(b"a/b/c"), None);
+
+        let header = CliAppHeader {
+            target_name: "v2.bin".to_owned(),
+            target_compression: "gzip".to_owned(),
+            source_name: "v1.bin".to_owned(),
+            source_compression: "bzip2/-9".to_owned(),
+        };
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(bytes, b"v2.bin/gzip/v1.bin/bzip2/-9");
+        assert_eq!(CliAppHeader::parse(&bytes), Some(header));
+
+        let header = CliAppHeader {
+            target_name: "out/v2.bin".to_owned(),
+            source_name: "in/v1.bin".to_owned(),
+            ..CliAppHeader::default()
+        };
+        assert!(matches!(
+            header.to_bytes(),
+            Err(Error::InvalidConfig { msg }) if msg == "target_name must not contain a slash"
+        ));
+        let header = CliAppHeader {
+            target_name: "v2.bin".to_owned(),
+            ..header
+        };
+        assert!(matches!(
+            header.to_bytes(),
+            Err(Error::InvalidConfig { msg }) if msg == "source_name must not contain a slash"
+        ));
+        // Without the paths, the names round-trip.
+        let header = CliAppHeader {
+            source_name: "v1.bin".to_owned(),
+            source_compression: "xz/-9e/--threads=0".to_owned(),
+            ..header
+        };
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(CliAppHeader::parse(&bytes), Some(header));
+    }
+
+    #[test]
+    fn matcher_params_round_trip() {
+        let fixure_path = "xdelta3/xdelta3/examples/iOS/xdelta3-ios-test/xdelta3-ios-test/";
+        let source = read_file(&format!("{}/{}", fixure_path, "file_v1.bin"));
+        let input = read_file(&format!("{}/{}", fixure_path, "file_v2.bin"));
+
+        let params = [
+            MatcherParams::default(),
+            MatcherParams {
+                large_look: 16,
+                large_step: 8,
+                small_chain: 1,
+                small_lchain: 1,
+                ..MatcherParams::default()
+            },
+            MatcherParams {
+                large_step: 1,
+                small_chain: 64,
+                small_lchain: 16,
+                max_lazy: 128,
+                long_enough: 256,
+                ..MatcherParams::default()
+            },
+        ];
+        for &params in &params {
+            let config = EncoderConfig::new().matcher_params(params);
+            let patch = encode_with(&input, &source, &config).expect("failed to encode");
+            assert_eq!(input, check_decode(&patch, &source));
+        }
+
+        let invalid = [
+            MatcherParams {
+                large_look: 3,
+                ..MatcherParams::default()
+            },
+            MatcherParams {
+                large_step: 0,
+                ..MatcherParams::default()
+            },
+            MatcherParams {
+                small_lchain: MatcherParams::default().small_chain + 1,
+                ..MatcherParams::default()
+            },
+            MatcherParams {
+                long_enough: 3,
+                max_lazy: 0,
+                ..MatcherParams::default()
+            },
+            MatcherParams {
+                max_lazy: MatcherParams::default().long_enough + 1,
+                ..MatcherParams::default()
+            },
+        ];
+        for &params in &invalid {
+            assert!(params.validate().is_err());
+            let config = EncoderConfig::new().matcher_params(params);
+            let result = encode_with(&input, &source, &config);
+            assert!(matches!(result, Err(Error::InvalidConfig { .. })));
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        let result = encode_with(&[1, 2, 3], &[1, 2, 3], &EncoderConfig::new().level(10));
+        assert!(matches!(result, Err(Error::InvalidConfig { .. })));
+        let result = decode_with(&[1, 2, 3], &[1, 2, 3], &DecoderConfig::new().window_size(1));
+        assert!(matches!(result, Err(Error::InvalidConfig { .. })));
     }
 }